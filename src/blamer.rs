@@ -0,0 +1,246 @@
+//! mod blamer works out which commits last touched the lines that a diff changes
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+
+use git2::Blame;
+use git2::BlameOptions;
+use git2::Commit;
+use git2::Delta;
+use git2::Diff;
+use git2::Oid;
+use git2::Patch;
+use git2::Repository;
+
+use crate::patcher::DiffHunks;
+use crate::patcher::HunkId;
+use crate::rebaser::Fixup;
+
+/// The commits that last touched the lines that a single hunk modifies
+pub(crate) struct HunkBlame {
+    pub(crate) id: HunkId,
+    /// Every commit that last touched a line removed by the hunk
+    ///
+    /// For hunks that only add lines this is the commits that last touched the
+    /// lines surrounding the addition. Empty if the hunk can't be blamed at all.
+    pub(crate) commits: HashSet<Oid>,
+}
+
+/// Blame every hunk of `diff` against HEAD
+///
+/// `diff` must be a diff from the HEAD tree, like the staged diff from
+/// `patcher::create_diff`. If `oldest` is provided blame will not look past it.
+pub(crate) fn blame_hunks(
+    repo: &Repository,
+    diff: &Diff<'_>,
+    oldest: Option<Oid>,
+) -> Result<Vec<HunkBlame>, anyhow::Error> {
+    let head = repo.head()?.peel_to_commit()?.id();
+    let mut blames: HashMap<&Path, Blame> = HashMap::new();
+    let mut hunk_blames = Vec::new();
+    for (delta_idx, delta) in diff.deltas().enumerate() {
+        let patch = Patch::from_diff(diff, delta_idx)?;
+        let num_hunks = patch.as_ref().map(|p| p.num_hunks()).unwrap_or(0);
+        let path = match (delta.status(), delta.old_file().path()) {
+            (Delta::Modified | Delta::Deleted | Delta::Renamed, Some(path)) => Some(path),
+            _ => None,
+        };
+        // Files without hunks can't be blamed but are still addressable as a
+        // single hunk, see `DiffHunks`
+        if num_hunks == 0 {
+            hunk_blames.push(HunkBlame {
+                id: (delta_idx, 0),
+                commits: HashSet::new(),
+            });
+        }
+        for hunk_idx in 0..num_hunks {
+            let commits = match (path, &patch) {
+                (Some(path), Some(patch)) => {
+                    if !blames.contains_key(path) {
+                        let mut opts = BlameOptions::new();
+                        opts.newest_commit(head);
+                        if let Some(oldest) = oldest {
+                            opts.oldest_commit(oldest);
+                        }
+                        blames.insert(path, repo.blame_file(path, Some(&mut opts))?);
+                    }
+                    let blame = &blames[path];
+                    blamed_lines(patch, hunk_idx)?
+                        .into_iter()
+                        .filter_map(|line| blame.get_line(line).map(|h| h.final_commit_id()))
+                        .collect()
+                }
+                _ => HashSet::new(),
+            };
+            hunk_blames.push(HunkBlame {
+                id: (delta_idx, hunk_idx),
+                commits,
+            });
+        }
+    }
+    Ok(hunk_blames)
+}
+
+/// The old-side line numbers that should be blamed to find a hunk's owner
+fn blamed_lines(patch: &Patch<'_>, hunk_idx: usize) -> Result<Vec<usize>, anyhow::Error> {
+    let mut removed = Vec::new();
+    // (context line before the first addition, context line after the last addition)
+    let mut surrounding = (None, None);
+    let mut seen_addition = false;
+    for line_idx in 0..patch.num_lines_in_hunk(hunk_idx)? {
+        let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+        match line.origin() {
+            '-' => removed.extend(line.old_lineno()),
+            '+' => seen_addition = true,
+            ' ' if !seen_addition => surrounding.0 = line.old_lineno(),
+            ' ' if surrounding.1.is_none() => surrounding.1 = line.old_lineno(),
+            _ => {}
+        }
+    }
+    if removed.is_empty() {
+        removed.extend(surrounding.0);
+        removed.extend(surrounding.1);
+    }
+    Ok(removed.into_iter().map(|l| l as usize).collect())
+}
+
+/// Staged changes split up by the commit that they should be absorbed into
+pub(crate) struct Absorbed<'a> {
+    /// One fixup per commit that had hunks assigned to it, oldest first
+    pub(crate) fixups: Vec<Fixup<'a>>,
+    /// Descriptions of the hunks that couldn't be assigned to exactly one commit
+    pub(crate) unassigned: Vec<String>,
+}
+
+/// Assign each hunk of `diff` to the candidate commit that last touched all of its lines
+pub(crate) fn absorb<'a>(
+    repo: &'a Repository,
+    diff: &Diff<'_>,
+    candidates: &[Commit<'a>],
+    oldest: Option<Oid>,
+) -> Result<Absorbed<'a>, anyhow::Error> {
+    let hunks = DiffHunks::from_diff(diff)?;
+    let candidate_ids: HashSet<Oid> = candidates.iter().map(|c| c.id()).collect();
+    let mut assigned: HashMap<HunkId, Oid> = HashMap::new();
+    let mut unassigned = Vec::new();
+    for hunk in blame_hunks(repo, diff, oldest)? {
+        let reason = match hunk.commits.len() {
+            0 => "no existing lines to blame",
+            1 => {
+                let commit = *hunk.commits.iter().next().unwrap();
+                if candidate_ids.contains(&commit) {
+                    assigned.insert(hunk.id, commit);
+                    continue;
+                }
+                "last touched outside of the commits in range"
+            }
+            _ => "last touched by multiple commits",
+        };
+        unassigned.push(format!("{} ({reason})", hunks.describe(hunk.id)));
+    }
+
+    let head_tree = repo.head()?.peel_to_tree()?;
+    let mut fixups = Vec::new();
+    for target in candidates.iter().rev() {
+        let diff = hunks.select(|id| assigned.get(&id) == Some(&target.id()))?;
+        if let Some(diff) = diff {
+            fixups.push(Fixup {
+                target: target.clone(),
+                diff,
+                base: head_tree.clone(),
+                message: None,
+            });
+        }
+    }
+    Ok(Absorbed { fixups, unassigned })
+}
+
+/// How many hunks of a diff were last touched by each commit
+pub(crate) struct Suggestions {
+    counts: HashMap<Oid, usize>,
+    total: usize,
+}
+
+impl Suggestions {
+    pub(crate) fn from_blames<'b>(
+        hunk_blames: impl IntoIterator<Item = &'b HunkBlame>,
+    ) -> Suggestions {
+        let mut counts: HashMap<Oid, usize> = HashMap::new();
+        let mut total = 0;
+        for hunk in hunk_blames {
+            total += 1;
+            for commit in &hunk.commits {
+                *counts.entry(*commit).or_default() += 1;
+            }
+        }
+        Suggestions { counts, total }
+    }
+
+    /// Describe how many hunks a commit last touched, if any
+    pub(crate) fn annotation(&self, commit: Oid) -> Option<String> {
+        self.counts.get(&commit).map(|count| {
+            let plural = if self.total == 1 { "" } else { "s" };
+            format!("[{count}/{} hunk{plural} last touched here]", self.total)
+        })
+    }
+
+    /// The index of the candidate that last touched the most hunks
+    ///
+    /// Ties go to the earliest candidate, i.e. the newest commit.
+    pub(crate) fn best_match(&self, candidates: impl Iterator<Item = Oid>) -> Option<usize> {
+        candidates
+            .enumerate()
+            .filter_map(|(i, oid)| self.counts.get(&oid).map(|count| (i, *count)))
+            .max_by_key(|(i, count)| (*count, Reverse(*i)))
+            .map(|(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oid(n: u8) -> Oid {
+        Oid::from_bytes(&[n; 20]).unwrap()
+    }
+
+    fn blame(id: HunkId, commits: &[u8]) -> HunkBlame {
+        HunkBlame {
+            id,
+            commits: commits.iter().map(|n| oid(*n)).collect(),
+        }
+    }
+
+    #[test]
+    fn best_match_is_the_commit_that_touched_the_most_hunks() {
+        let blames = [
+            blame((0, 0), &[1, 2]),
+            blame((0, 1), &[2]),
+            blame((1, 0), &[]),
+        ];
+        let suggestions = Suggestions::from_blames(&blames);
+        assert_eq!(
+            suggestions.best_match([1, 2, 3].map(oid).into_iter()),
+            Some(1)
+        );
+        assert_eq!(
+            suggestions.annotation(oid(2)).as_deref(),
+            Some("[2/3 hunks last touched here]")
+        );
+        assert_eq!(suggestions.annotation(oid(3)), None);
+    }
+
+    #[test]
+    fn best_match_ties_go_to_the_newest_commit() {
+        let blames = [blame((0, 0), &[1]), blame((0, 1), &[2])];
+        let suggestions = Suggestions::from_blames(&blames);
+        assert_eq!(
+            suggestions.best_match([3, 2, 1].map(oid).into_iter()),
+            Some(1)
+        );
+        assert_eq!(suggestions.best_match([3].map(oid).into_iter()), None);
+        assert_eq!(suggestions.best_match(std::iter::empty()), None);
+    }
+}
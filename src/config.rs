@@ -1,6 +1,11 @@
 use std::env;
+use std::fmt;
+use std::io::IsTerminal as _;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use clap::Parser;
+use clap::Subcommand;
 
 // Env vars that provide defaults for args
 const MAX_COMMITS_VAR: &str = "GIT_INSTAFIX_MAX_COMMITS";
@@ -9,6 +14,12 @@ const UPSTREAM_VAR: &str = "GIT_INSTAFIX_UPSTREAM";
 pub const UPSTREAM_SETTING: &str = "instafix.default-upstream-branch";
 const REQUIRE_NEWLINE_VAR: &str = "GIT_INSTAFIX_REQUIRE_NEWLINE";
 const REQUIRE_NEWLINE_SETTING: &str = "instafix.require-newline";
+const SQUASH_SETTING: &str = "instafix.squash";
+const KEEP_COMMITTER_SETTING: &str = "instafix.keep-committer";
+const STOP_ON_CONFLICT_SETTING: &str = "instafix.stop-on-conflict";
+const UPDATE_REFS_SETTING: &str = "instafix.update-refs";
+const INCLUDE_UNTRACKED_SETTING: &str = "instafix.include-untracked";
+const WORKTREE_BRANCHES_SETTING: &str = "instafix.worktree-branches";
 const THEME_VAR: &str = "GIT_INSTAFIX_THEME";
 const THEME_SETTING: &str = "instafix.theme";
 
@@ -35,23 +46,98 @@ When run with no arguments this will:
     max_term_width = 100
 )]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Change the commit message that you amend, instead of using the original commit message
-    #[clap(short = 's', long, hide = true)]
+    ///
+    /// Your editor is opened with the original message, like `git commit
+    /// --amend`. core.commentChar and commit.cleanup are honoured. Pass
+    /// --squash=false to override git config.
+    ///
+    /// [gitconfig: instafix.squash]
+    #[clap(
+        short = 's',
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
     squash: Option<bool>,
-    /// The maximum number of commits to show when looking for your merge point
+
+    /// Use this as the new message of the amended commit instead of opening an editor
+    ///
+    /// Implies --squash. Can't be used when amending more than one commit.
+    /// There is no -m for this like `git commit` has, since -m is --max-commits.
+    #[clap(long, global = true, conflicts_with = "message_file")]
+    message: Option<String>,
+
+    /// Read the new message of the amended commit from a file, or stdin if it is -
+    ///
+    /// Implies --squash. Can't be used when amending more than one commit.
+    #[clap(short = 'F', long = "file", global = true)]
+    message_file: Option<PathBuf>,
+    /// The number of commits to show at a time when looking for your merge point
+    ///
+    /// More can be loaded from the selector. Commit message patterns, --absorb
+    /// and --split only consider this many commits.
     ///
     /// [gitconfig: instafix.max-commits]
-    #[clap(short = 'm', long = "max-commits", env = MAX_COMMITS_VAR)]
+    #[clap(short = 'm', long = "max-commits", env = MAX_COMMITS_VAR, global = true)]
     max_commits: Option<usize>,
 
     /// Specify a commit to ammend by the subject line of the commit
-    #[clap(short = 'P', long)]
+    ///
+    /// If more than one commit matches you will be asked to choose between them,
+    /// or it is an error if there is no terminal to ask on.
+    #[clap(short = 'P', long, global = true)]
     commit_message_pattern: Option<String>,
 
+    /// Treat the commit message pattern as a regular expression
+    #[clap(long, global = true, requires = "commit_message_pattern")]
+    pattern_regex: bool,
+
+    /// What to match the commit message pattern against
+    ///
+    /// One of: summary, message (the full commit message), author, trailer (the
+    /// value of any trailer) or trailer:<key> (the value of a specific trailer)
+    #[clap(
+        long,
+        global = true,
+        requires = "commit_message_pattern",
+        default_value = "summary"
+    )]
+    pattern_scope: PatternScope,
+
+    /// Specify a commit to amend by any revision, e.g. a sha, HEAD~3 or :/message
+    ///
+    /// The commit must be between HEAD and the upstream merge-base.
+    #[clap(long, global = true, conflicts_with = "commit_message_pattern")]
+    commit: Option<String>,
+
+    /// Fix up every commit that last touched a staged hunk, instead of picking one commit
+    ///
+    /// Each staged hunk is blamed and applied to the commit in range that last
+    /// touched all of the lines that it changes. Hunks that can't be assigned to
+    /// exactly one commit are left staged.
+    #[clap(long, conflicts_with_all = ["commit_message_pattern", "commit"])]
+    absorb: bool,
+
+    /// Choose a commit to amend for each staged file or hunk, instead of picking one commit
+    ///
+    /// All of the chosen commits are amended in a single rebase. Anything that
+    /// isn't assigned to a commit is left staged.
+    #[clap(
+        long,
+        conflicts_with_all = ["commit_message_pattern", "commit", "absorb", "non_interactive"]
+    )]
+    split: bool,
+
     /// The branch to not go past when looking for your merge point
     ///
     /// [gitconfig: instafix.default-upstream-branch]
-    #[clap(short = 'u', long, env = UPSTREAM_VAR)]
+    #[clap(short = 'u', long, env = UPSTREAM_VAR, global = true)]
     default_upstream_branch: Option<String>,
 
     /// Require a newline when confirming y/n questions
@@ -60,6 +146,119 @@ struct Args {
     #[clap(long, env = REQUIRE_NEWLINE_VAR)]
     require_newline: Option<bool>,
 
+    /// Never prompt, fail instead if a question would need to be asked
+    ///
+    /// This is turned on automatically when stdin is not a terminal. Choose the
+    /// commit to amend with --commit, --commit-message-pattern or --absorb, and
+    /// use --all if nothing is staged. Exits with status 3 if instafix would
+    /// have needed to ask something.
+    #[clap(long, visible_alias = "yes", global = true)]
+    non_interactive: bool,
+
+    /// Stop with the conflict in the working tree if rewriting a later commit conflicts
+    ///
+    /// Resolve it and run with --continue to carry on, or --abort to put
+    /// everything back. Without this nothing is changed and your changes are
+    /// left staged. Pass --stop-on-conflict=false to override git config.
+    ///
+    /// [gitconfig: instafix.stop-on-conflict]
+    #[clap(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    stop_on_conflict: Option<bool>,
+
+    /// Keep the original committer and commit date of commits that are only moved
+    ///
+    /// Only the commits that are amended get you as their committer. Pass
+    /// --keep-committer=false to override git config.
+    ///
+    /// [gitconfig: instafix.keep-committer]
+    #[clap(
+        long,
+        global = true,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    keep_committer: Option<bool>,
+
+    /// Set the commit date of every rewritten commit to its author date
+    #[clap(long, global = true)]
+    committer_date_is_author_date: bool,
+
+    /// Make yourself the author of the amended commit, like `git commit --amend --reset-author`
+    #[clap(long, global = true)]
+    reset_author: bool,
+
+    /// Don't run the pre-commit, commit-msg and post-rewrite hooks
+    #[clap(long, global = true)]
+    no_verify: bool,
+
+    /// Also move refs matching GLOB that point at rewritten commits, like 'refs/tags/wip-*'
+    ///
+    /// Local branches are always moved. This can be given more than once, and
+    /// adds to the globs from git config. Annotated tags are never moved.
+    ///
+    /// [gitconfig: instafix.update-refs]
+    #[clap(long = "update-refs", value_name = "GLOB", global = true)]
+    update_refs: Vec<String>,
+
+    /// Also stash untracked files before rebasing in the working tree with --stop-on-conflict
+    ///
+    /// Use this if later commits add files that you have untracked copies of.
+    /// Pass --include-untracked=false to override git config.
+    ///
+    /// [gitconfig: instafix.include-untracked]
+    #[clap(
+        long,
+        global = true,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    include_untracked: Option<bool>,
+
+    /// What to do with branches checked out in other worktrees that would be moved
+    ///
+    /// One of: skip (leave them where they are, with a warning), update (move
+    /// them and update that worktree's index and files, as long as it has no
+    /// changes to the files being fixed) or refuse (don't change anything).
+    /// Defaults to skip.
+    ///
+    /// [gitconfig: instafix.worktree-branches]
+    #[clap(long, value_name = "MODE", global = true)]
+    worktree_branches: Option<WorktreeBranches>,
+
+    /// Carry on after resolving a conflict that --stop-on-conflict stopped at
+    #[clap(
+        long = "continue",
+        conflicts_with_all = ["commit_message_pattern", "commit", "absorb", "split", "dry_run"]
+    )]
+    continue_run: bool,
+
+    /// Give up on a run that --stop-on-conflict stopped, putting all branches back
+    #[clap(
+        long = "abort",
+        conflicts_with_all = ["commit_message_pattern", "commit", "absorb", "split", "dry_run", "continue_run"]
+    )]
+    abort_run: bool,
+
+    /// Print the commits and branches that would be rewritten, without changing anything
+    ///
+    /// HEAD, the index, the stash and all refs are left alone.
+    #[clap(short = 'n', long, global = true)]
+    dry_run: bool,
+
+    /// If nothing is staged, stage all changes to tracked files without asking
+    #[clap(short = 'a', long)]
+    all: bool,
+
     /// Show the possible color themes for output
     #[clap(long)]
     help_themes: bool,
@@ -69,30 +268,158 @@ struct Args {
     theme: Option<String>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Move every branch that the last instafix run rewrote back to where it was
+    ///
+    /// Each run is recorded under refs/instafix/, so running this repeatedly
+    /// undoes earlier runs as well. Nothing is undone if any of the branches
+    /// have moved since.
+    Undo,
+    /// Change the message of a commit without changing its contents
+    ///
+    /// The commit is chosen the same way as the commit to amend, and nothing
+    /// needs to be staged. Your editor is opened with its message unless you pass
+    /// --message or --file.
+    Reword,
+}
+
 /// Fully configured arguments after loading from env and gitconfig
 pub struct Config {
     /// Change the commit message that you amend, instead of using the original commit message
     pub squash: bool,
-    /// The maximum number of commits to show when looking for your merge point
+    /// The new message for the amended commit
+    pub message: Option<String>,
+    /// A file to read the new message for the amended commit from
+    pub message_file: Option<PathBuf>,
+    /// The number of commits to show at a time when looking for your merge point
     pub max_commits: usize,
     /// Specify a commit to ammend by the subject line of the commit
     pub commit_message_pattern: Option<String>,
+    /// Treat the commit message pattern as a regex
+    pub pattern_regex: bool,
+    /// What to match the commit message pattern against
+    pub pattern_scope: PatternScope,
+    /// Specify a commit to amend by revision
+    pub commit: Option<String>,
+    /// Distribute staged hunks to the commits that last touched them
+    pub absorb: bool,
+    /// Interactively assign staged files or hunks to commits
+    pub split: bool,
     pub default_upstream_branch: Option<String>,
     /// Require a newline when confirming y/n questions
     pub require_newline: bool,
+    /// Fail instead of prompting
+    pub non_interactive: bool,
+    /// Stage all changes to tracked files if nothing is staged
+    pub all: bool,
+    /// Only print what would be rewritten
+    pub dry_run: bool,
+    /// Leave conflicts in the working tree instead of aborting
+    pub stop_on_conflict: bool,
+    /// Keep the committer of commits that are only moved
+    pub keep_committer: bool,
+    /// Use author dates as commit dates
+    pub committer_date_is_author_date: bool,
+    /// Make the current user the author of amended commits
+    pub reset_author: bool,
+    /// Skip git hooks
+    pub no_verify: bool,
+    /// Globs of refs, besides local branches, to move to rewritten commits
+    pub update_refs: Vec<String>,
+    /// Stash untracked files too before rebasing in the working tree
+    pub include_untracked: bool,
+    /// How to treat branches checked out in other worktrees
+    pub worktree_branches: WorktreeBranches,
+    /// Continue a run that stopped at a conflict
+    pub continue_run: bool,
+    /// Abort a run that stopped at a conflict
+    pub abort_run: bool,
+    /// Undo the last instafix run instead of doing a new one
+    pub undo: bool,
+    /// Only change the message of the chosen commit
+    pub reword: bool,
     /// User requested info about themes
     pub help_themes: bool,
     /// Which theme to use
     pub theme: String,
 }
 
+/// The part of a commit that `--commit-message-pattern` is matched against
+#[derive(Clone, Debug)]
+pub enum PatternScope {
+    Summary,
+    Message,
+    Author,
+    /// A trailer value, optionally only for trailers with the given key
+    Trailer(Option<String>),
+}
+
+impl FromStr for PatternScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "summary" => Ok(PatternScope::Summary),
+            "message" => Ok(PatternScope::Message),
+            "author" => Ok(PatternScope::Author),
+            "trailer" => Ok(PatternScope::Trailer(None)),
+            _ => match s.strip_prefix("trailer:") {
+                Some(key) if !key.is_empty() => Ok(PatternScope::Trailer(Some(key.to_owned()))),
+                _ => Err(format!(
+                    "expected one of summary, message, author, trailer or trailer:<key>, got {s:?}"
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for PatternScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternScope::Summary => f.write_str("summary"),
+            PatternScope::Message => f.write_str("message"),
+            PatternScope::Author => f.write_str("author"),
+            PatternScope::Trailer(None) => f.write_str("trailers"),
+            PatternScope::Trailer(Some(key)) => write!(f, "{key} trailer"),
+        }
+    }
+}
+
+/// What to do with a branch that would move while it is checked out in another worktree
+#[derive(Clone, Debug)]
+pub enum WorktreeBranches {
+    Skip,
+    Update,
+    Refuse,
+}
+
+impl FromStr for WorktreeBranches {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(WorktreeBranches::Skip),
+            "update" => Ok(WorktreeBranches::Update),
+            "refuse" => Ok(WorktreeBranches::Refuse),
+            _ => Err(format!("expected one of skip, update or refuse, got {s:?}")),
+        }
+    }
+}
+
 /// Create a Config based on arguments and env vars
 pub fn load_config_from_args_env_git() -> Config {
     let mut args = Args::parse();
     if env::args().next().unwrap().ends_with("squash") {
         args.squash = Some(true)
     }
-    args_to_config_using_git_config(args).unwrap()
+    match args_to_config_using_git_config(args) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error: {:#}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn args_to_config_using_git_config(args: Args) -> Result<Config, anyhow::Error> {
@@ -100,19 +427,57 @@ fn args_to_config_using_git_config(args: Args) -> Result<Config, anyhow::Error>
     let repo = git2::Repository::discover(".")?;
     cfg.add_file(&repo.path().join("config"), git2::ConfigLevel::Local, false)?;
     Ok(Config {
-        squash: args
-            .squash
-            .unwrap_or_else(|| cfg.get_bool("instafix.squash").unwrap_or(false)),
+        squash: args.message.is_some()
+            || args.message_file.is_some()
+            || args
+                .squash
+                .unwrap_or_else(|| cfg.get_bool(SQUASH_SETTING).unwrap_or(false)),
+        message: args.message,
+        message_file: args.message_file,
         max_commits: args
             .max_commits
             .unwrap_or_else(|| cfg.get_i32(MAX_COMMITS_SETTING).unwrap_or(15) as usize),
         commit_message_pattern: args.commit_message_pattern,
+        pattern_regex: args.pattern_regex,
+        pattern_scope: args.pattern_scope,
+        commit: args.commit,
+        absorb: args.absorb,
+        split: args.split,
         default_upstream_branch: args
             .default_upstream_branch
             .or_else(|| cfg.get_string(UPSTREAM_SETTING).ok()),
         require_newline: args
             .require_newline
             .unwrap_or_else(|| cfg.get_bool(REQUIRE_NEWLINE_SETTING).unwrap_or(false)),
+        non_interactive: args.non_interactive || !std::io::stdin().is_terminal(),
+        all: args.all,
+        dry_run: args.dry_run,
+        stop_on_conflict: args
+            .stop_on_conflict
+            .unwrap_or_else(|| cfg.get_bool(STOP_ON_CONFLICT_SETTING).unwrap_or(false)),
+        keep_committer: args
+            .keep_committer
+            .unwrap_or_else(|| cfg.get_bool(KEEP_COMMITTER_SETTING).unwrap_or(false)),
+        committer_date_is_author_date: args.committer_date_is_author_date,
+        reset_author: args.reset_author,
+        no_verify: args.no_verify,
+        update_refs: update_refs(&cfg, args.update_refs)?,
+        include_untracked: args
+            .include_untracked
+            .unwrap_or_else(|| cfg.get_bool(INCLUDE_UNTRACKED_SETTING).unwrap_or(false)),
+        worktree_branches: match args.worktree_branches {
+            Some(mode) => mode,
+            None => match cfg.get_string(WORKTREE_BRANCHES_SETTING) {
+                Ok(setting) => setting
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid {WORKTREE_BRANCHES_SETTING}: {e}"))?,
+                Err(_) => WorktreeBranches::Skip,
+            },
+        },
+        continue_run: args.continue_run,
+        abort_run: args.abort_run,
+        undo: matches!(args.command, Some(Command::Undo)),
+        reword: matches!(args.command, Some(Command::Reword)),
         help_themes: args.help_themes,
         theme: args.theme.unwrap_or_else(|| {
             cfg.get_string(THEME_SETTING)
@@ -120,3 +485,16 @@ fn args_to_config_using_git_config(args: Args) -> Result<Config, anyhow::Error>
         }),
     })
 }
+
+/// The `--update-refs` globs followed by every `instafix.update-refs` value
+fn update_refs(cfg: &git2::Config, mut globs: Vec<String>) -> Result<Vec<String>, anyhow::Error> {
+    let mut entries = cfg.multivar(UPDATE_REFS_SETTING, None)?;
+    while let Some(entry) = entries.next() {
+        if let Some(glob) = entry?.value() {
+            if !globs.iter().any(|g| g == glob) {
+                globs.push(glob.to_owned());
+            }
+        }
+    }
+    Ok(globs)
+}
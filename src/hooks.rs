@@ -0,0 +1,112 @@
+//! mod hooks runs the git hooks that `git commit` and `git rebase` would run
+
+use std::fs;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::bail;
+use anyhow::Context as _;
+use git2::Oid;
+use git2::Repository;
+
+/// Run the pre-commit hook on the staged changes, failing if it does
+pub(crate) fn pre_commit(repo: &Repository) -> Result<(), anyhow::Error> {
+    if !run(repo, "pre-commit", &[], None)? {
+        bail!("The pre-commit hook failed, nothing has been changed\nUse --no-verify to skip it");
+    }
+    Ok(())
+}
+
+/// Run the commit-msg hook on `message`, returning the message as the hook left it
+pub(crate) fn commit_msg(repo: &Repository, message: &str) -> Result<String, anyhow::Error> {
+    if hook_path(repo, "commit-msg")?.is_none() {
+        return Ok(message.to_owned());
+    }
+    let path = repo.path().join("INSTAFIX_EDITMSG");
+    fs::write(&path, message).context("writing commit message for the commit-msg hook")?;
+    if !run(repo, "commit-msg", &[&path.to_string_lossy()], None)? {
+        bail!("The commit-msg hook failed, nothing has been changed\nUse --no-verify to skip it");
+    }
+    fs::read_to_string(&path).context("reading commit message from the commit-msg hook")
+}
+
+/// Run the post-rewrite hook with each (original, rewritten) commit
+///
+/// Like git, a failing post-rewrite hook doesn't fail the run.
+pub(crate) fn post_rewrite(
+    repo: &Repository,
+    rewritten: &[(Oid, Oid)],
+) -> Result<(), anyhow::Error> {
+    if rewritten.is_empty() {
+        return Ok(());
+    }
+    let stdin = rewritten
+        .iter()
+        .map(|(original, new)| format!("{original} {new}\n"))
+        .collect::<String>();
+    if !run(repo, "post-rewrite", &["rebase"], Some(&stdin))? {
+        eprintln!("Warning: the post-rewrite hook failed");
+    }
+    Ok(())
+}
+
+/// Run the hook called `name` if there is one, returning whether it succeeded
+fn run(
+    repo: &Repository,
+    name: &str,
+    args: &[&str],
+    stdin: Option<&str>,
+) -> Result<bool, anyhow::Error> {
+    let Some(hook) = hook_path(repo, name)? else {
+        return Ok(true);
+    };
+    let mut cmd = Command::new(&hook);
+    cmd.args(args);
+    // hooks run from the top of the working tree, like they do for git
+    if let Some(workdir) = repo.workdir() {
+        cmd.current_dir(workdir);
+    }
+    cmd.stdin(if stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("running the {name} hook"))?;
+    if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        // the hook may exit without reading its input
+        let _ = pipe.write_all(input.as_bytes());
+    }
+    Ok(child.wait()?.success())
+}
+
+/// The hook called `name` in `core.hooksPath` or the repository's hooks directory, if it is executable
+fn hook_path(repo: &Repository, name: &str) -> Result<Option<PathBuf>, anyhow::Error> {
+    let dir = match repo.config()?.get_path("core.hooksPath") {
+        Ok(dir) if dir.is_relative() => repo.workdir().unwrap_or(repo.path()).join(dir),
+        Ok(dir) => dir,
+        Err(_) => common_dir(repo)?.join("hooks"),
+    };
+    let path = dir.join(name);
+    let Ok(metadata) = fs::metadata(&path) else {
+        return Ok(None);
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Ok(None);
+        }
+    }
+    Ok(metadata.is_file().then_some(path))
+}
+
+/// The git directory shared by every worktree of `repo`
+pub(crate) fn common_dir(repo: &Repository) -> Result<PathBuf, anyhow::Error> {
+    match fs::read_to_string(repo.path().join("commondir")) {
+        Ok(common) => Ok(repo.path().join(common.trim_end())),
+        Err(_) => Ok(repo.path().to_owned()),
+    }
+}
@@ -1,49 +1,547 @@
+mod blamer;
 mod config;
+mod hooks;
+mod message;
+mod notes;
+mod oplog;
 mod patcher;
 mod rebaser;
+mod resume;
 mod selecter;
+mod signer;
+mod worktrees;
 
-use anyhow::Context;
-use git2::{Branch, Commit, Repository};
+use anyhow::{bail, Context};
+use dialoguer::Confirm;
+use git2::{Branch, Commit, Diff, Oid, Repository, StashApplyOptions, StashFlags};
 use syntect::highlighting::ThemeSet;
 
 pub use config::load_config_from_args_env_git;
+use rebaser::Fixup;
+use resume::SavedRun;
 
 pub fn instafix(c: config::Config) -> Result<(), anyhow::Error> {
     let repo = Repository::open_from_env().context("opening repo")?;
-    let diff = patcher::create_diff(&repo, &c.theme, c.require_newline).context("creating diff")?;
+    if SavedRun::exists(&repo) {
+        bail!(
+            "An earlier instafix run stopped at a conflict\n\
+            Run `git instafix --continue` or `git instafix --abort` first"
+        );
+    }
+    if c.reword && (c.absorb || c.split) {
+        bail!("--absorb and --split can't be used to reword a commit");
+    }
+    let diff = if c.reword {
+        patcher::empty_diff(&repo)?
+    } else {
+        patcher::create_diff(
+            &repo,
+            &c.theme,
+            c.require_newline,
+            c.all,
+            c.non_interactive,
+            c.dry_run,
+        )
+        .context("creating diff")?
+    };
+    let diff = if !c.no_verify && !c.reword && !c.dry_run {
+        hooks::pre_commit(&repo)?;
+        // the hook may have changed what is staged
+        patcher::staged_diff(&repo)?
+    } else {
+        diff
+    };
     let head = repo.head().context("finding head commit")?;
     let head_branch = Branch::wrap(head);
     let upstream =
         selecter::get_merge_base(&repo, &head_branch, c.default_upstream_branch.as_deref())
             .context("creating merge base")?;
-    let commit_to_amend = selecter::select_commit_to_amend(
-        &repo,
-        upstream,
-        c.max_commits,
-        c.commit_message_pattern.as_deref(),
-    )
-    .context("selecting commit to amend")?;
-    eprintln!("Selected {}", commit_display(&commit_to_amend));
-    patcher::do_fixup_commit(&repo, &head_branch, &commit_to_amend, c.squash)
-        .context("doing fixup commit")?;
-    let needs_stash = patcher::worktree_is_dirty(&repo)?;
-    if needs_stash {
+    let mut fixups = if c.absorb {
+        absorb_fixups(&repo, &diff, upstream.as_ref(), c.max_commits)?
+    } else if c.split {
+        selecter::split_between_commits(
+            &repo,
+            upstream.as_ref(),
+            c.max_commits,
+            &diff,
+            &c.theme,
+            c.non_interactive,
+        )
+        .context("assigning changes to commits")?
+    } else {
+        let commit_to_amend = if let Some(rev) = c.commit.as_deref() {
+            selecter::find_commit_by_rev(&repo, upstream.as_ref(), rev)?
+        } else {
+            let message_pattern = c
+                .commit_message_pattern
+                .as_deref()
+                .map(|p| selecter::MessagePattern::new(p, c.pattern_regex, c.pattern_scope.clone()))
+                .transpose()?;
+            selecter::select_commit_to_amend(
+                &repo,
+                upstream.as_ref(),
+                c.max_commits,
+                message_pattern.as_ref(),
+                &diff,
+                &c.theme,
+                c.non_interactive,
+            )
+            .context("selecting commit to amend")?
+        };
+        eprintln!("Selected {}", commit_display(&commit_to_amend));
+        vec![Fixup {
+            target: commit_to_amend,
+            diff,
+            base: repo.head()?.peel_to_tree()?,
+            message: None,
+        }]
+    };
+    let refs = rebaser::RefUpdates {
+        globs: c.update_refs.clone(),
+        skipped: worktrees::check(&repo, &c.worktree_branches, &fixups)?,
+    };
+    if c.dry_run {
+        return print_plan(&repo, &head_branch, &fixups, &refs);
+    }
+    if c.squash || c.reword {
+        squash_messages(&repo, &mut fixups, &c)?;
+    }
+    if !c.no_verify {
+        for fixup in &mut fixups {
+            match &fixup.message {
+                // the message is being changed, so it's the one to check
+                Some(message) => fixup.message = Some(hooks::commit_msg(&repo, message)?),
+                // a fixup keeps the target's message, like `git commit --fixup`
+                None => {
+                    hooks::commit_msg(&repo, &format!("fixup! {}\n", fixup.target.id()))?;
+                }
+            }
+        }
+    }
+    let snapshot = oplog::RefSnapshot::take(&repo, &refs.globs)?;
+    let signer = checked_signer(&repo, c.non_interactive, c.require_newline)?;
+    let identities = rebaser::Identities {
+        keep_committer: c.keep_committer,
+        committer_date_is_author_date: c.committer_date_is_author_date,
+        reset_author: c.reset_author,
+    };
+    let rewrite =
+        rebaser::rewrite_in_memory(&repo, &head_branch, &fixups, signer.as_ref(), identities)
+            .context("rewriting commits")?;
+    match rewrite.conflict {
+        None => {
+            for b in rebaser::update_refs(&repo, &rewrite, &refs)? {
+                println!("{}", b);
+            }
+            snapshot
+                .record(&repo)
+                .context("recording operation for undo")?;
+            let rewritten = rewrite
+                .commits
+                .iter()
+                .map(|commit| (commit.original, commit.rewritten))
+                .collect::<Vec<_>>();
+            let amended = fixups.iter().map(|f| f.target.id()).collect::<Vec<_>>();
+            notes::copy(&repo, &rewritten, &amended).context("copying notes")?;
+            if !c.no_verify {
+                hooks::post_rewrite(&repo, &rewritten)?;
+            }
+            Ok(())
+        }
+        Some(conflict) if !c.stop_on_conflict => bail!(
+            "Rewriting {} conflicts with your changes, nothing has been changed\n\
+            Use --stop-on-conflict to resolve the conflict in the working tree",
+            commit_display(&repo.find_commit(conflict)?)
+        ),
+        Some(_) if rebaser::range_has_merges(&repo, &head_branch, &fixups)? => bail!(
+            "Rewriting conflicts with your changes, nothing has been changed\n\
+            --stop-on-conflict can't be used when the commits being rewritten include merges"
+        ),
+        Some(conflict) if repo.find_commit(conflict)?.parent_count() == 0 => bail!(
+            "Rewriting {} conflicts with your changes, nothing has been changed\n\
+            --stop-on-conflict can't stop at a root commit",
+            commit_display(&repo.find_commit(conflict)?)
+        ),
+        // Only a real rebase can leave conflicts in the working tree to be resolved
+        Some(_) => {
+            if signer.is_some() {
+                eprintln!("Warning: commits rewritten in the working tree will not be signed");
+            }
+            rebase_in_worktree(
+                &repo,
+                &head_branch,
+                &fixups,
+                snapshot,
+                identities,
+                &refs,
+                &c,
+            )
+        }
+    }
+}
+
+/// Get the new message for each fixup's target, from the command line or an editor
+fn squash_messages(
+    repo: &Repository,
+    fixups: &mut [Fixup],
+    c: &config::Config,
+) -> Result<(), anyhow::Error> {
+    let given = match (&c.message, &c.message_file) {
+        (Some(message), _) => Some(message.clone()),
+        (None, Some(path)) => Some(message::read_file(path)?),
+        (None, None) => None,
+    };
+    if given.is_some() && fixups.len() > 1 {
+        bail!("--message and --file can only be used when amending a single commit");
+    }
+    for fixup in fixups {
+        fixup.message = Some(match &given {
+            Some(given) => message::given(repo, given)?,
+            None => message::edit(repo, &fixup.target, c.non_interactive)
+                .with_context(|| format!("editing message of {}", commit_display(&fixup.target)))?,
+        });
+    }
+    Ok(())
+}
+
+/// The signer configured by `commit.gpgsign`, once it is known to work
+///
+/// If signing is configured but doesn't work this warns and asks whether to
+/// rewrite commits without signing them.
+fn checked_signer(
+    repo: &Repository,
+    non_interactive: bool,
+    require_newline: bool,
+) -> Result<Option<signer::Signer>, anyhow::Error> {
+    let checked = signer::Signer::from_config(repo).and_then(|signer| match signer {
+        Some(signer) => signer.check(repo).map(|_| Some(signer)),
+        None => Ok(None),
+    });
+    match checked {
+        Ok(signer) => Ok(signer),
+        Err(e) => {
+            eprintln!("Warning: commit.gpgsign is set but commits can't be signed: {e:#}");
+            if non_interactive {
+                return Err(InteractionRequired(
+                    "Not rewriting commits without signing them when running non-interactively"
+                        .into(),
+                )
+                .into());
+            }
+            if Confirm::new()
+                .with_prompt("Rewrite commits without signing them?")
+                .wait_for_newline(require_newline)
+                .interact()?
+            {
+                Ok(None)
+            } else {
+                bail!("")
+            }
+        }
+    }
+}
+
+/// Rewrite the branch by committing the fixups at HEAD and rebasing, stopping at conflicts
+///
+/// Unlike `rebaser::rewrite_in_memory` this checks out every commit along the
+/// way, so the working tree is stashed first.
+fn rebase_in_worktree(
+    repo: &Repository,
+    head_branch: &Branch,
+    fixups: &[Fixup],
+    snapshot: oplog::RefSnapshot,
+    identities: rebaser::Identities,
+    refs: &rebaser::RefUpdates,
+    c: &config::Config,
+) -> Result<(), anyhow::Error> {
+    let fixup_commits =
+        patcher::do_fixup_commits(repo, head_branch, fixups).context("doing fixup commit")?;
+    let stash = if patcher::worktree_is_dirty(repo, c.include_untracked)? {
         // TODO: is it reasonable to create a new repo to work around lifetime issues?
         let mut repo = Repository::open_from_env()?;
         let sig = repo.signature()?.clone();
-        repo.stash_save(&sig, "git-instafix stashing changes", None)?;
+        let mut flags = StashFlags::DEFAULT;
+        if c.include_untracked {
+            flags |= StashFlags::INCLUDE_UNTRACKED;
+        }
+        Some(repo.stash_save(&sig, "git-instafix stashing changes", Some(flags))?)
+    } else {
+        None
+    };
+    // stashing happened through a different handle, so our index may be stale
+    let rebased = repo
+        .index()
+        .and_then(|mut index| index.read(true))
+        .map_err(anyhow::Error::from)
+        .and_then(|()| {
+            let current_branch = Branch::wrap(repo.head()?);
+            rebaser::do_rebase(
+                repo,
+                &current_branch,
+                fixups,
+                &fixup_commits,
+                identities,
+                refs,
+            )
+        });
+    let rebased = match rebased {
+        Ok(rebased) => rebased,
+        Err(e) => {
+            let e = with_stash_restored(stash, e);
+            // even a failed rebase leaves the fixup commits and any retargeted branches behind
+            snapshot
+                .record(repo)
+                .context("recording operation for undo")?;
+            return Err(e);
+        }
+    };
+    let run = SavedRun {
+        fixups: fixups
+            .iter()
+            .zip(&fixup_commits)
+            .map(|(f, c)| (f.target.id(), *c))
+            .collect(),
+        branches: Vec::new(),
+        stash,
+        snapshot,
+        identities,
+        no_verify: c.no_verify,
+        fixup_applied: false,
+    };
+    finish_run(repo, run, rebased)
+}
+
+/// Carry on with a run that stopped at a conflict, once the conflict is resolved
+pub fn continue_run() -> Result<(), anyhow::Error> {
+    let repo = Repository::open_from_env().context("opening repo")?;
+    let run = SavedRun::load(&repo)?;
+    let fixups = run.load_fixups(&repo)?;
+    let branches = rebaser::RepoBranches::from_pending(&repo, &run.branches)?;
+    let rebased = rebaser::continue_rebase(
+        &repo,
+        &fixups,
+        &run.fixup_commits(),
+        branches,
+        run.identities,
+        run.fixup_applied,
+    )?;
+    finish_run(&repo, run, rebased)
+}
+
+/// Throw away a run that stopped at a conflict, putting every branch back where it was
+///
+/// The changes that were being applied are left staged.
+pub fn abort_run() -> Result<(), anyhow::Error> {
+    let repo = Repository::open_from_env().context("opening repo")?;
+    let run = SavedRun::load(&repo)?;
+    rebaser::abort_rebase(&repo)?;
+    // libgit2 won't apply a stash on top of staged changes, which is what
+    // moving the branch back off the fixup commits leaves, so pop it first
+    let popped = run.stash.map(pop_stash).transpose();
+    for restored in run.snapshot.restore(&repo)? {
+        println!("{restored}");
+    }
+    SavedRun::clear(&repo)?;
+    popped?;
+    Ok(())
+}
+
+/// Save the run if the rebase stopped at a conflict, otherwise record it and put the stash back
+fn finish_run(
+    repo: &Repository,
+    mut run: SavedRun,
+    rebased: rebaser::Rebased,
+) -> Result<(), anyhow::Error> {
+    let rewritten = match rebased {
+        rebaser::Rebased::Finished(rewritten) => rewritten,
+        rebaser::Rebased::Stopped(conflict) => {
+            run.branches = conflict.branches.pending()?;
+            run.fixup_applied = conflict.fixup_applied;
+            run.save(repo)?;
+            bail!(
+                "Stopped at a conflict while applying {}\n\
+                Resolve the conflicts and `git add` the files, then run `git instafix --continue`.\n\
+                Run `git instafix --abort` to put everything back the way it was.",
+                commit_display(&repo.find_commit(conflict.commit)?)
+            );
+        }
+    };
+    if let Err(e) = record_rewrite(repo, &run, &rewritten) {
+        return Err(with_stash_restored(run.stash, e));
+    }
+    if let Some(stash) = run.stash {
+        pop_stash(stash)?;
+    }
+    Ok(())
+}
+
+/// Bookkeeping for a finished rewrite: the undo log, notes and the post-rewrite hook
+fn record_rewrite(
+    repo: &Repository,
+    run: &SavedRun,
+    rewritten: &[(Oid, Oid)],
+) -> Result<(), anyhow::Error> {
+    if SavedRun::exists(repo) {
+        SavedRun::clear(repo)?;
+    }
+    run.snapshot
+        .record(repo)
+        .context("recording operation for undo")?;
+    let amended = run
+        .fixups
+        .iter()
+        .map(|(target, _)| *target)
+        .collect::<Vec<_>>();
+    notes::copy(repo, rewritten, &amended).context("copying notes")?;
+    if !run.no_verify {
+        hooks::post_rewrite(repo, rewritten)?;
+    }
+    Ok(())
+}
+
+/// Put the stash back after `error` ended a run early
+///
+/// If that fails as well, both messages are kept so that the user can tell
+/// where their uncommitted changes went.
+fn with_stash_restored(stash: Option<Oid>, error: anyhow::Error) -> anyhow::Error {
+    match stash.map(pop_stash) {
+        Some(Err(pop_error)) => anyhow::anyhow!("{error:#}\n{pop_error:#}"),
+        _ => error,
+    }
+}
+
+/// Apply and drop the stash that was made before rebasing in the working tree
+///
+/// The stash is found by its id rather than its position, since more may have
+/// been pushed while stopped at a conflict. If it doesn't apply cleanly it is
+/// kept, and the error explains how to get the changes back.
+fn pop_stash(stash: Oid) -> Result<(), anyhow::Error> {
+    let mut repo = Repository::open_from_env()?;
+    let mut index = None;
+    repo.stash_foreach(|i, _message, oid| {
+        if *oid == stash {
+            index = Some(i);
+        }
+        index.is_none()
+    })?;
+    let Some(index) = index else {
+        bail!(
+            "The changes stashed before rewriting are no longer in the stash list\n\
+            If they weren't applied already, run `git stash apply --index {stash}` to get them back"
+        );
+    };
+    // Restore the index as well so that hunks that weren't absorbed stay staged
+    let mut opts = StashApplyOptions::new();
+    opts.reinstantiate_index();
+    if let Err(e) = repo.stash_apply(index, Some(&mut opts)) {
+        bail!(
+            "Your uncommitted changes couldn't be put back: {}\n\
+            They are still saved as stash@{{{index}}} ({stash}).\n\
+            Run `git stash pop --index stash@{{{index}}}` to apply them and resolve the conflicts,\n\
+            or `git instafix undo` first to put the branches back where they were.",
+            e.message()
+        );
     }
-    let current_branch = Branch::wrap(repo.head()?);
-    rebaser::do_rebase(&repo, &current_branch, &commit_to_amend, &diff)?;
-    if needs_stash {
-        let mut repo = Repository::open(".")?;
-        repo.stash_pop(0, None)?;
+    // libgit2 leaves conflicts in the index instead of failing
+    if repo.index()?.has_conflicts() {
+        bail!(
+            "Your uncommitted changes conflict with the rewritten commits\n\
+            Resolve the conflicts in the working tree, then run `git stash drop stash@{{{index}}}`.\n\
+            Until then the changes are still saved as stash@{{{index}}} ({stash})."
+        );
+    }
+    repo.stash_drop(index)?;
+    Ok(())
+}
+
+/// Put back every ref that the last instafix run moved
+pub fn undo() -> Result<(), anyhow::Error> {
+    let repo = Repository::open_from_env().context("opening repo")?;
+    for restored in oplog::undo(&repo)? {
+        println!("{restored}");
     }
+    Ok(())
+}
 
+/// Describe what applying `fixups` would do, without changing anything
+fn print_plan(
+    repo: &Repository,
+    head_branch: &Branch,
+    fixups: &[Fixup],
+    refs: &rebaser::RefUpdates,
+) -> Result<(), anyhow::Error> {
+    let rewrite = rebaser::rewrite_in_memory(repo, head_branch, fixups, None, Default::default())
+        .context("computing rewritten commits")?;
+    let branches = rebaser::RepoBranches::for_repo(repo, refs)?;
+    println!("Dry run, nothing has been changed");
+    for commit in &rewrite.commits {
+        let original = repo.find_commit(commit.original)?;
+        let verb = if commit.amended { "amend" } else { "rewrite" };
+        println!(
+            "    {verb:<8}{} -> {}",
+            commit_display(&original),
+            &commit.rewritten.to_string()[..10]
+        );
+    }
+    if let Some(conflict) = rewrite.conflict {
+        let conflict = repo.find_commit(conflict)?;
+        println!("    {:<8}{}", "conflict", commit_display(&conflict));
+        println!("Rewriting would stop at the conflict, so no branches would be updated");
+        return Ok(());
+    }
+    for branch in branches.rewritten(&rewrite)? {
+        println!(
+            "would update {}: {} -> {}",
+            branch.label(),
+            &branch.from.to_string()[..10],
+            &branch.to.to_string()[..10]
+        );
+    }
     Ok(())
 }
 
+/// Split the staged changes up between the commits that last touched them
+fn absorb_fixups<'a>(
+    repo: &'a Repository,
+    diff: &Diff<'_>,
+    upstream: Option<&selecter::CommitSelection>,
+    max_commits: usize,
+) -> Result<Vec<Fixup<'a>>, anyhow::Error> {
+    let candidates = selecter::commits_in_range(repo, upstream, max_commits)?;
+    let absorbed = blamer::absorb(repo, diff, &candidates, upstream.map(|u| u.commit.id()))
+        .context("assigning hunks to commits")?;
+    if !absorbed.unassigned.is_empty() {
+        eprintln!("Leaving hunks staged that could not be assigned to a single commit:");
+        for hunk in &absorbed.unassigned {
+            eprintln!("    {hunk}");
+        }
+    }
+    if absorbed.fixups.is_empty() {
+        bail!("No staged hunks could be assigned to a commit");
+    }
+    for fixup in &absorbed.fixups {
+        eprintln!("Absorbing into {}", commit_display(&fixup.target));
+    }
+    Ok(absorbed.fixups)
+}
+
+/// Instafix needed to ask a question but is running non-interactively
+#[derive(Debug)]
+pub struct InteractionRequired(pub(crate) String);
+
+impl InteractionRequired {
+    /// The status that instafix exits with when this is the cause of an error
+    pub const EXIT_CODE: i32 = 3;
+}
+
+impl std::fmt::Display for InteractionRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InteractionRequired {}
+
 /// Display a commit as "short_hash summary"
 fn commit_display(commit: &Commit) -> String {
     format!(
@@ -15,6 +15,7 @@
 use std::env;
 
 use git_instafix::load_config_from_args_env_git;
+use git_instafix::InteractionRequired;
 
 fn main() {
     let config = load_config_from_args_env_git();
@@ -24,7 +25,16 @@ fn main() {
         return;
     }
 
-    if let Err(e) = git_instafix::instafix(config) {
+    let result = if config.undo {
+        git_instafix::undo()
+    } else if config.continue_run {
+        git_instafix::continue_run()
+    } else if config.abort_run {
+        git_instafix::abort_run()
+    } else {
+        git_instafix::instafix(config)
+    };
+    if let Err(e) = result {
         // An empty message means don't display any error message
         let msg = e.to_string();
         if !msg.is_empty() {
@@ -34,6 +44,9 @@ fn main() {
                 println!("Error: {:#}", e);
             }
         }
+        if e.chain().any(|cause| cause.is::<InteractionRequired>()) {
+            std::process::exit(InteractionRequired::EXIT_CODE);
+        }
         std::process::exit(1);
     }
 }
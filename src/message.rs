@@ -0,0 +1,175 @@
+//! mod message gets the new commit message for a squash, from an editor or the command line
+
+use std::fs;
+use std::io::Read as _;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::bail;
+use anyhow::Context as _;
+use git2::{Commit, Config, Repository};
+
+use crate::commit_display;
+use crate::InteractionRequired;
+
+const SCISSORS: &str = "------------------------ >8 ------------------------";
+
+/// Characters to choose from when `core.commentChar` is `auto`, in git's order
+const AUTO_COMMENT_CHARS: &str = "#;@!$%^&|:";
+
+/// How to clean up a message, from `commit.cleanup`
+enum Cleanup {
+    Strip,
+    Whitespace,
+    Verbatim,
+    Scissors,
+}
+
+/// Open the user's editor to change `commit`'s message
+///
+/// Without a terminal the editor is only run if `GIT_EDITOR` is set, since
+/// that is how scripts choose an editor.
+pub(crate) fn edit(
+    repo: &Repository,
+    commit: &Commit,
+    non_interactive: bool,
+) -> Result<String, anyhow::Error> {
+    let cfg = repo.config()?;
+    if non_interactive && std::env::var_os("GIT_EDITOR").is_none() {
+        return Err(InteractionRequired(
+            "Not opening an editor for the new commit message when running non-interactively\n\
+            Pass the message with --message or --file"
+                .into(),
+        )
+        .into());
+    }
+    let original = commit.message().unwrap_or_default();
+    let comment = comment_char(&cfg, original)?;
+    let cleanup = cleanup_mode(&cfg, true)?;
+
+    let mut template = original.trim_end().to_owned();
+    template.push_str("\n\n");
+    let display = commit_display(commit);
+    match cleanup {
+        Cleanup::Scissors => template.push_str(&format!(
+            "{comment} {SCISSORS}\n\
+            {comment} Do not modify or remove the line above.\n\
+            {comment} Everything below it will be ignored.\n\
+            {comment}\n\
+            {comment} Editing the message of {display}\n"
+        )),
+        Cleanup::Strip => template.push_str(&format!(
+            "{comment} Please enter the new message for {display}. Lines starting\n\
+            {comment} with '{comment}' will be ignored, and an empty message aborts the amend.\n"
+        )),
+        Cleanup::Whitespace | Cleanup::Verbatim => template.push_str(&format!(
+            "{comment} Please enter the new message for {display}. Lines starting\n\
+            {comment} with '{comment}' will be kept; you may remove them yourself if you want to.\n\
+            {comment} An empty message aborts the amend.\n"
+        )),
+    }
+
+    let path = repo.path().join("INSTAFIX_EDITMSG");
+    fs::write(&path, template).context("writing commit message template")?;
+    run_editor(&cfg, &path)?;
+    let edited = fs::read_to_string(&path).context("reading edited commit message")?;
+    clean(&edited, comment, cleanup)
+}
+
+/// Clean up a message given with `--message` or `--file`, the way `git commit -m` would
+pub(crate) fn given(repo: &Repository, message: &str) -> Result<String, anyhow::Error> {
+    let cfg = repo.config()?;
+    let comment = comment_char(&cfg, message)?;
+    clean(message, comment, cleanup_mode(&cfg, false)?)
+}
+
+/// Read a message from `path`, or from stdin if it is `-`
+pub(crate) fn read_file(path: &Path) -> Result<String, anyhow::Error> {
+    if path == Path::new("-") {
+        let mut message = String::new();
+        std::io::stdin()
+            .read_to_string(&mut message)
+            .context("reading commit message from stdin")?;
+        return Ok(message);
+    }
+    fs::read_to_string(path).with_context(|| format!("reading commit message from {path:?}"))
+}
+
+fn clean(message: &str, comment: char, cleanup: Cleanup) -> Result<String, anyhow::Error> {
+    let cleaned = match cleanup {
+        Cleanup::Verbatim => message.to_owned(),
+        Cleanup::Whitespace => git2::message_prettify(message, None)?,
+        Cleanup::Strip => git2::message_prettify(message, Some(comment as u8))?,
+        Cleanup::Scissors => {
+            let scissors = format!("{comment} {SCISSORS}");
+            let kept = match message.lines().position(|l| l == scissors) {
+                Some(n) => message.lines().take(n).collect::<Vec<_>>().join("\n"),
+                None => message.to_owned(),
+            };
+            git2::message_prettify(kept, None)?
+        }
+    };
+    if cleaned.trim().is_empty() {
+        bail!("Aborting because the commit message is empty");
+    }
+    Ok(cleaned)
+}
+
+/// The comment character from `core.commentChar`, choosing one that isn't used by `message` for `auto`
+fn comment_char(cfg: &Config, message: &str) -> Result<char, anyhow::Error> {
+    match cfg.get_string("core.commentChar").as_deref() {
+        Err(_) => Ok('#'),
+        Ok("auto") => AUTO_COMMENT_CHARS
+            .chars()
+            .find(|c| !message.lines().any(|l| l.starts_with(*c)))
+            .ok_or_else(|| anyhow::anyhow!("Unable to choose a comment character for the message")),
+        Ok(setting) => match setting.chars().collect::<Vec<_>>()[..] {
+            [c] if c.is_ascii() => Ok(c),
+            _ => bail!("core.commentChar should be a single ASCII character, not {setting:?}"),
+        },
+    }
+}
+
+/// The `commit.cleanup` mode, where `default` depends on whether the message was edited
+fn cleanup_mode(cfg: &Config, edited: bool) -> Result<Cleanup, anyhow::Error> {
+    Ok(match cfg.get_string("commit.cleanup").as_deref() {
+        Err(_) | Ok("default") if edited => Cleanup::Strip,
+        Err(_) | Ok("default") => Cleanup::Whitespace,
+        Ok("strip") => Cleanup::Strip,
+        Ok("whitespace") => Cleanup::Whitespace,
+        Ok("verbatim") => Cleanup::Verbatim,
+        Ok("scissors") if edited => Cleanup::Scissors,
+        Ok("scissors") => Cleanup::Whitespace,
+        Ok(other) => bail!("Invalid commit.cleanup mode {other:?}"),
+    })
+}
+
+/// Run the editor that `git commit` would use on `path`, and wait for it to exit
+fn run_editor(cfg: &Config, path: &Path) -> Result<(), anyhow::Error> {
+    let terminal_is_dumb = std::env::var("TERM").map_or(true, |t| t == "dumb");
+    let editor = std::env::var("GIT_EDITOR")
+        .ok()
+        .or_else(|| cfg.get_string("core.editor").ok())
+        .or_else(|| {
+            (!terminal_is_dumb)
+                .then(|| std::env::var("VISUAL").ok())
+                .flatten()
+        })
+        .or_else(|| std::env::var("EDITOR").ok())
+        .unwrap_or_else(|| "vi".to_owned());
+    if editor == ":" {
+        return Ok(());
+    }
+    // Like git, let the shell split the editor's arguments
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$@\""))
+        .arg(&editor)
+        .arg(path)
+        .status()
+        .with_context(|| format!("running editor {editor:?}"))?;
+    if !status.success() {
+        bail!("There was a problem with the editor {editor:?}");
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! mod notes copies notes from rewritten commits to their replacements, the way `git rebase` does
+
+use anyhow::bail;
+use git2::{Config, Oid, Repository};
+
+/// How to combine a copied note with a note that the new commit already has, from `notes.rewriteMode`
+enum Mode {
+    Overwrite,
+    Concatenate,
+    CatSortUniq,
+    Ignore,
+}
+
+/// Copy notes from each (original, rewritten) commit in `rewritten`
+///
+/// Which notes are copied is configured the same way as for git: only the
+/// refs matching `notes.rewriteRef` (or `GIT_NOTES_REWRITE_REF`), and not for
+/// `amended` commits if `notes.rewrite.amend` is false or for any others if
+/// `notes.rewrite.rebase` is false.
+pub(crate) fn copy(
+    repo: &Repository,
+    rewritten: &[(Oid, Oid)],
+    amended: &[Oid],
+) -> Result<(), anyhow::Error> {
+    let cfg = repo.config()?;
+    let notes_refs = notes_refs(repo, &cfg)?;
+    if notes_refs.is_empty() {
+        return Ok(());
+    }
+    let mode = mode(&cfg)?;
+    let copy_amended = cfg.get_bool("notes.rewrite.amend").unwrap_or(true);
+    let copy_rebased = cfg.get_bool("notes.rewrite.rebase").unwrap_or(true);
+    let sig = repo.signature()?;
+    for notes_ref in &notes_refs {
+        for (original, new) in rewritten {
+            let enabled = if amended.contains(original) {
+                copy_amended
+            } else {
+                copy_rebased
+            };
+            if !enabled {
+                continue;
+            }
+            let Ok(note) = repo.find_note(Some(notes_ref), *original) else {
+                continue;
+            };
+            let copied = note.message().unwrap_or_default();
+            let message = match repo.find_note(Some(notes_ref), *new) {
+                Err(_) => copied.to_owned(),
+                Ok(existing) => {
+                    let existing = existing.message().unwrap_or_default();
+                    match mode {
+                        Mode::Overwrite => copied.to_owned(),
+                        Mode::Ignore => continue,
+                        Mode::Concatenate => {
+                            format!("{}\n\n{copied}", existing.trim_end_matches('\n'))
+                        }
+                        Mode::CatSortUniq => {
+                            let mut lines = existing
+                                .lines()
+                                .chain(copied.lines())
+                                .filter(|l| !l.is_empty())
+                                .collect::<Vec<_>>();
+                            lines.sort_unstable();
+                            lines.dedup();
+                            lines.iter().map(|l| format!("{l}\n")).collect()
+                        }
+                    }
+                }
+            };
+            repo.note(&note.author(), &sig, Some(notes_ref), *new, &message, true)?;
+        }
+    }
+    Ok(())
+}
+
+/// Every notes ref matching `notes.rewriteRef`, which is a list of globs with no default
+fn notes_refs(repo: &Repository, cfg: &Config) -> Result<Vec<String>, anyhow::Error> {
+    let globs = match std::env::var("GIT_NOTES_REWRITE_REF") {
+        Ok(globs) => globs.split(':').map(str::to_owned).collect(),
+        Err(_) => {
+            let mut globs = vec![];
+            let mut entries = cfg.multivar("notes.rewriteRef", None)?;
+            while let Some(entry) = entries.next() {
+                if let Some(glob) = entry?.value() {
+                    globs.push(glob.to_owned());
+                }
+            }
+            globs
+        }
+    };
+    let mut refs = vec![];
+    for glob in globs.iter().filter(|g| g.starts_with("refs/notes/")) {
+        for reference in repo.references_glob(glob)? {
+            if let Some(name) = reference?.name() {
+                if !refs.iter().any(|r| r == name) {
+                    refs.push(name.to_owned());
+                }
+            }
+        }
+    }
+    Ok(refs)
+}
+
+fn mode(cfg: &Config) -> Result<Mode, anyhow::Error> {
+    let setting = match std::env::var("GIT_NOTES_REWRITE_MODE") {
+        Ok(mode) => Some(mode),
+        Err(_) => cfg.get_string("notes.rewriteMode").ok(),
+    };
+    Ok(match setting.as_deref() {
+        None | Some("concatenate") => Mode::Concatenate,
+        Some("overwrite") => Mode::Overwrite,
+        Some("cat_sort_uniq") => Mode::CatSortUniq,
+        Some("ignore") => Mode::Ignore,
+        Some(other) => bail!("Invalid notes.rewriteMode {other:?}"),
+    })
+}
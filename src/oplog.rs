@@ -0,0 +1,192 @@
+//! mod oplog records which refs an instafix run moved, so that the whole run can be undone at once
+
+use std::collections::HashMap;
+
+use anyhow::bail;
+use anyhow::Context as _;
+use git2::Oid;
+use git2::Repository;
+
+/// Points at a blob describing the most recent operation
+///
+/// Each line of the blob is `<ref> <before> <after>`, plus a `previous <blob>`
+/// line pointing at the operation before it, if there was one.
+pub(crate) const LOG_REF: &str = "refs/instafix/log";
+
+const PREVIOUS: &str = "previous";
+
+/// Where HEAD, every local branch and every other ref the operation may move pointed before it
+pub(crate) struct RefSnapshot(pub(crate) HashMap<String, Oid>);
+
+/// A ref that was moved by an operation
+pub(crate) struct MovedRef {
+    pub(crate) name: String,
+    pub(crate) before: Oid,
+    pub(crate) after: Oid,
+}
+
+impl std::fmt::Display for MovedRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let after = &self.after.to_string()[..15];
+        let before = &self.before.to_string()[..15];
+        let name = &self.name;
+        f.write_fmt(format_args!("restored {name}: {after} -> {before}"))
+    }
+}
+
+impl RefSnapshot {
+    /// Snapshot HEAD if it is detached, every local branch, and every ref matching `update_refs`
+    pub(crate) fn take(
+        repo: &Repository,
+        update_refs: &[String],
+    ) -> Result<RefSnapshot, anyhow::Error> {
+        let mut refs = current_refs(repo)?;
+        for glob in update_refs {
+            for reference in repo.references_glob(glob)? {
+                let reference = reference?;
+                if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+                    refs.insert(name.to_owned(), oid);
+                }
+            }
+        }
+        Ok(RefSnapshot(refs))
+    }
+
+    /// Add an operation to the log for every ref that has moved since the snapshot
+    ///
+    /// Nothing is recorded if no refs have moved.
+    pub(crate) fn record(&self, repo: &Repository) -> Result<(), anyhow::Error> {
+        let moved = self.moved(repo)?;
+        if moved.is_empty() {
+            return Ok(());
+        }
+
+        // Entries are blobs rather than commits so that they don't show up in
+        // `git log --all` or `:/message` searches
+        let mut entry = String::new();
+        if let Ok(previous) = repo.refname_to_id(LOG_REF) {
+            entry.push_str(&format!("{PREVIOUS} {previous}\n"));
+        }
+        for m in &moved {
+            entry.push_str(&format!("{} {} {}\n", m.name, m.before, m.after));
+        }
+        let entry = repo.blob(entry.as_bytes())?;
+        repo.reference(LOG_REF, entry, true, "git-instafix record operation")?;
+        Ok(())
+    }
+
+    /// Move every ref that has moved since the snapshot back, without recording an operation
+    pub(crate) fn restore(&self, repo: &Repository) -> Result<Vec<MovedRef>, anyhow::Error> {
+        let moved = self.moved(repo)?;
+        let sig = repo.signature()?;
+        let mut tx = repo.transaction()?;
+        for m in &moved {
+            tx.lock_ref(&m.name)?;
+            tx.set_target(&m.name, m.before, Some(&sig), "git-instafix abort")?;
+        }
+        tx.commit().context("updating refs")?;
+        Ok(moved)
+    }
+
+    /// The refs that have moved since the snapshot, sorted by name
+    fn moved(&self, repo: &Repository) -> Result<Vec<MovedRef>, anyhow::Error> {
+        let head_detached = repo.head_detached()?;
+        let mut moved = self
+            .0
+            .iter()
+            .filter_map(|(name, before)| {
+                // HEAD only counts while it is detached, otherwise it moved with its branch
+                let after = if name == "HEAD" && !head_detached {
+                    None
+                } else {
+                    repo.refname_to_id(name).ok()
+                };
+                match after {
+                    Some(after) if after != *before => Some(MovedRef {
+                        name: name.clone(),
+                        before: *before,
+                        after,
+                    }),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        moved.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(moved)
+    }
+}
+
+/// HEAD, if it is detached, and every local branch
+fn current_refs(repo: &Repository) -> Result<HashMap<String, Oid>, anyhow::Error> {
+    let mut refs = HashMap::new();
+    if repo.head_detached()? {
+        refs.insert("HEAD".to_owned(), repo.head()?.peel_to_commit()?.id());
+    }
+    for (branch, _type) in repo.branches(Some(git2::BranchType::Local))?.flatten() {
+        let reference = branch.into_reference();
+        if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+            refs.insert(name.to_owned(), oid);
+        }
+    }
+    Ok(refs)
+}
+
+/// Move every ref touched by the most recent operation back to where it was, and drop it from the log
+///
+/// Refuses to do anything if any of the refs have moved since the operation.
+pub(crate) fn undo(repo: &Repository) -> Result<Vec<MovedRef>, anyhow::Error> {
+    let entry_id = match repo.refname_to_id(LOG_REF) {
+        Ok(entry_id) => entry_id,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            bail!("No instafix operations to undo")
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let entry = repo.find_blob(entry_id)?;
+    let entry = std::str::from_utf8(entry.content()).context("reading operation log")?;
+    let (previous, moved) =
+        parse_entry(entry).with_context(|| format!("reading operation {entry_id}"))?;
+
+    for m in &moved {
+        let current = repo.refname_to_id(&m.name).ok();
+        if current != Some(m.after) {
+            bail!(
+                "{} has changed since the last instafix operation, not undoing anything",
+                m.name
+            );
+        }
+    }
+
+    let sig = repo.signature()?;
+    let mut tx = repo.transaction()?;
+    tx.lock_ref(LOG_REF)?;
+    for m in &moved {
+        tx.lock_ref(&m.name)?;
+        tx.set_target(&m.name, m.before, Some(&sig), "git-instafix undo")?;
+    }
+    match previous {
+        Some(previous) => tx.set_target(LOG_REF, previous, Some(&sig), "git-instafix undo")?,
+        None => tx.remove(LOG_REF)?,
+    }
+    tx.commit().context("updating refs")?;
+    Ok(moved)
+}
+
+/// Parse an operation into the previous operation and the refs that it moved
+fn parse_entry(entry: &str) -> Result<(Option<Oid>, Vec<MovedRef>), anyhow::Error> {
+    let mut previous = None;
+    let mut moved = Vec::new();
+    for line in entry.lines() {
+        let parts = line.split(' ').collect::<Vec<_>>();
+        match parts[..] {
+            [PREVIOUS, oid] => previous = Some(Oid::from_str(oid)?),
+            [name, before, after] => moved.push(MovedRef {
+                name: name.to_owned(),
+                before: Oid::from_str(before)?,
+                after: Oid::from_str(after)?,
+            }),
+            _ => bail!("unexpected line in operation log: {line:?}"),
+        }
+    }
+    Ok((previous, moved))
+}
@@ -1,33 +1,77 @@
 //! mod patcher creates a patch/commit that represents the change to apply in the later rebase
 
 mod diff_ui;
+mod hunks;
+
+use std::collections::HashSet;
+use std::path::Path;
 
 use anyhow::bail;
 use dialoguer::Confirm;
+use dialoguer::Select;
 use git2::Branch;
 use git2::Commit;
 use git2::Diff;
+use git2::DiffOptions;
+use git2::Oid;
 use git2::Repository;
+use git2::StatusOptions;
+use git2::Tree;
 use terminal_size::{terminal_size, Height};
 
 use diff_ui::native_diff;
 use diff_ui::print_diff_lines;
 use diff_ui::print_diffstat;
+pub(crate) use hunks::DiffHunks;
+pub(crate) use hunks::HunkId;
+
+use crate::commit_display;
+use crate::rebaser;
+use crate::rebaser::Fixup;
+use crate::InteractionRequired;
+
+/// A diff with no changes, for rewording a commit without changing its tree
+pub(crate) fn empty_diff(repo: &Repository) -> Result<Diff<'_>, anyhow::Error> {
+    let head_tree = repo.head()?.peel_to_tree()?;
+    Ok(repo.diff_tree_to_tree(Some(&head_tree), Some(&head_tree), None)?)
+}
+
+/// The changes staged in the index, reloading it in case something else wrote it
+pub(crate) fn staged_diff(repo: &Repository) -> Result<Diff<'_>, anyhow::Error> {
+    repo.index()?.read(false)?;
+    let head_tree = repo.head()?.peel_to_tree()?;
+    Ok(repo.diff_tree_to_index(Some(&head_tree), None, Some(&mut diff_opts()))?)
+}
 
 /// Get a diff either from the index or the diff from the index to the working tree
+///
+/// If nothing is staged, everything is staged without asking when `stage_all`
+/// is set. Otherwise it is an error to have nothing staged when `non_interactive`.
+/// With `dry_run` the changes chosen to stage are not written to the index.
 pub(crate) fn create_diff<'a>(
     repo: &'a Repository,
     theme: &str,
     require_newline: bool,
+    stage_all: bool,
+    non_interactive: bool,
+    dry_run: bool,
 ) -> Result<Diff<'a>, anyhow::Error> {
     let head = repo.head()?;
     let head_tree = head.peel_to_tree()?;
-    let staged_diff = repo.diff_tree_to_index(Some(&head_tree), None, None)?;
-    let dirty_diff = repo.diff_index_to_workdir(None, None)?;
+    let staged_diff = repo.diff_tree_to_index(Some(&head_tree), None, Some(&mut diff_opts()))?;
+    let dirty_diff = repo.diff_index_to_workdir(None, Some(&mut diff_opts()))?;
     let diffstat = staged_diff.stats()?;
     let diff = if diffstat.files_changed() == 0 {
         let dirty_workdir_stats = dirty_diff.stats()?;
-        if dirty_workdir_stats.files_changed() > 0 {
+        let to_stage = if dirty_workdir_stats.files_changed() > 0 && stage_all {
+            print_diffstat("Staging", &dirty_diff)?;
+            dirty_diff
+        } else if dirty_workdir_stats.files_changed() > 0 && non_interactive {
+            return Err(InteractionRequired(
+                "Nothing staged, stage some changes or use --all to stage everything".into(),
+            )
+            .into());
+        } else if dirty_workdir_stats.files_changed() > 0 {
             let Height(h) = terminal_size().map(|(_w, h)| h).unwrap_or(Height(24));
             let cutoff_height = (h - 5) as usize; // give some room for the prompt
             let total_change = dirty_workdir_stats.insertions() + dirty_workdir_stats.deletions();
@@ -41,21 +85,28 @@ pub(crate) fn create_diff<'a>(
                     print_diff_lines(&diff_lines)?;
                 }
             }
-            if !Confirm::new()
+            if Confirm::new()
                 .with_prompt("Nothing staged, stage and commit everything?")
                 .wait_for_newline(require_newline)
                 .interact()?
             {
+                dirty_diff
+            } else if Confirm::new()
+                .with_prompt("Choose files and hunks to stage instead?")
+                .wait_for_newline(require_newline)
+                .interact()?
+            {
+                match pick_hunks(&dirty_diff, theme, require_newline)? {
+                    Some(picked) => picked,
+                    None => bail!("Nothing chosen to stage"),
+                }
+            } else {
                 bail!("");
             }
         } else {
             bail!("Nothing staged and no tracked files have any changes");
-        }
-        repo.apply(&dirty_diff, git2::ApplyLocation::Index, None)?;
-        // the diff that we return knows whether it's from the index to the
-        // workdir or the HEAD to the index, so now that we've created a new
-        // commit we need a new diff.
-        repo.diff_tree_to_index(Some(&head_tree), None, None)?
+        };
+        stage(repo, &head_tree, &to_stage, dry_run)?
     } else {
         diff_ui::print_diffstat("Staged", &staged_diff)?;
         staged_diff
@@ -64,33 +115,313 @@ pub(crate) fn create_diff<'a>(
     Ok(diff)
 }
 
-pub(crate) fn worktree_is_dirty(repo: &Repository) -> Result<bool, anyhow::Error> {
+/// Stage `to_stage`, returning the diff from `head_tree` of everything now staged
+///
+/// With `dry_run` it is staged into a tree that nothing points at, leaving the
+/// index alone.
+fn stage<'a>(
+    repo: &'a Repository,
+    head_tree: &Tree<'_>,
+    to_stage: &Diff<'_>,
+    dry_run: bool,
+) -> Result<Diff<'a>, anyhow::Error> {
+    // the diff that we return knows whether it's from the index to the
+    // workdir or the HEAD to the index, so now that we've staged the
+    // changes we need a new diff.
+    if dry_run {
+        let mut idx = repo.apply_to_tree(head_tree, to_stage, None)?;
+        let tree = repo.find_tree(idx.write_tree_to(repo)?)?;
+        Ok(repo.diff_tree_to_tree(Some(head_tree), Some(&tree), Some(&mut diff_opts()))?)
+    } else {
+        repo.apply(to_stage, git2::ApplyLocation::Index, None)?;
+        Ok(repo.diff_tree_to_index(Some(head_tree), None, Some(&mut diff_opts()))?)
+    }
+}
+
+/// Walk through each file and hunk in `diff` asking which ones to include, like `git add -p`
+///
+/// Returns a diff of just the chosen hunks, or `None` if nothing was chosen.
+fn pick_hunks(
+    diff: &Diff<'_>,
+    theme: &str,
+    require_newline: bool,
+) -> Result<Option<Diff<'static>>, anyhow::Error> {
+    let hunks = DiffHunks::from_diff(diff)?;
+    choose_hunks(
+        &hunks,
+        |path, items| {
+            Ok(Select::new()
+                .with_prompt(format!("{}", path.display()))
+                .items(items)
+                .default(0)
+                .interact()?)
+        },
+        |id| {
+            if let Some(hunk_diff) = hunks.select(|h| h == id)? {
+                print_diff(&hunk_diff, theme)?;
+            }
+            Ok(Confirm::new()
+                .with_prompt(format!("Stage {}?", hunks.describe(id)))
+                .wait_for_newline(require_newline)
+                .interact()?)
+        },
+    )
+}
+
+/// The hunks chosen by `choose_file` and `stage_hunk`, as a diff
+///
+/// `choose_file` is given a file's path and what can be done with it, and
+/// returns the index of the choice. `stage_hunk` is asked about each hunk of the
+/// files where hunks are chosen one at a time.
+fn choose_hunks(
+    hunks: &DiffHunks,
+    mut choose_file: impl FnMut(&Path, &[&str]) -> Result<usize, anyhow::Error>,
+    mut stage_hunk: impl FnMut(HunkId) -> Result<bool, anyhow::Error>,
+) -> Result<Option<Diff<'static>>, anyhow::Error> {
+    let mut picked: HashSet<HunkId> = HashSet::new();
+    for (f, file) in hunks.files.iter().enumerate() {
+        let file_hunks = hunks.file_hunk_ids(f).collect::<Vec<_>>();
+        let mut items = vec!["Stage this file", "Skip this file"];
+        if file_hunks.len() > 1 {
+            items.push("Choose hunks");
+        }
+        match choose_file(&file.path, &items)? {
+            0 => picked.extend(file_hunks),
+            1 => {}
+            _ => {
+                for id in file_hunks {
+                    if stage_hunk(id)? {
+                        picked.insert(id);
+                    }
+                }
+            }
+        }
+    }
+    hunks.select(|id| picked.contains(&id))
+}
+
+/// Diff options that produce patches which can be applied, including binary files
+fn diff_opts() -> DiffOptions {
+    let mut opts = DiffOptions::new();
+    opts.show_binary(true);
+    opts
+}
+
+/// Print a syntax highlighted diff
+pub(crate) fn print_diff(diff: &Diff<'_>, theme: &str) -> Result<(), anyhow::Error> {
+    print_diff_lines(&native_diff(diff, theme)?)
+}
+
+/// The changes that a commit makes to its first parent, in a form that can be applied
+pub(crate) fn commit_diff<'a>(
+    repo: &'a Repository,
+    commit: &Commit<'_>,
+) -> Result<Diff<'a>, anyhow::Error> {
+    let parent_tree = match commit.parents().next() {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+    Ok(repo.diff_tree_to_tree(
+        parent_tree.as_ref(),
+        Some(&commit.tree()?),
+        Some(&mut diff_opts()),
+    )?)
+}
+
+/// The diffstat of a commit followed by its syntax highlighted patch, one line per entry
+pub(crate) fn commit_preview(
+    repo: &Repository,
+    commit: &Commit<'_>,
+    theme: &str,
+    width: usize,
+) -> Result<Vec<String>, anyhow::Error> {
+    let parent_tree = match commit.parents().next() {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+    let mut lines = diff_ui::diffstat(&diff, width)?
+        .lines()
+        .map(|l| l.to_owned())
+        .collect::<Vec<_>>();
+    lines.push(String::new());
+    let patch = native_diff(&diff, theme)?.concat();
+    // syntect colors carry across lines, reset them so that each line can be
+    // displayed on its own
+    lines.extend(
+        patch
+            .lines()
+            .map(|l| format!("{}\x1b[0m", l.replace('\t', "    "))),
+    );
+    Ok(lines)
+}
+
+/// Whether there are staged or unstaged changes, or untracked files if `include_untracked`
+pub(crate) fn worktree_is_dirty(
+    repo: &Repository,
+    include_untracked: bool,
+) -> Result<bool, anyhow::Error> {
     let head = repo.head()?;
     let head_tree = head.peel_to_tree()?;
     let staged_diff = repo.diff_tree_to_index(Some(&head_tree), None, None)?;
     let dirty_diff = repo.diff_index_to_workdir(None, None)?;
     let diffstat = staged_diff.stats()?;
     let dirty_workdir_stats = dirty_diff.stats()?;
-    Ok(diffstat.files_changed() > 0 || dirty_workdir_stats.files_changed() > 0)
+    if diffstat.files_changed() > 0 || dirty_workdir_stats.files_changed() > 0 {
+        return Ok(true);
+    }
+    if include_untracked {
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(true).recurse_untracked_dirs(true);
+        let statuses = repo.statuses(Some(&mut opts))?;
+        return Ok(statuses.iter().any(|s| s.status().is_wt_new()));
+    }
+    Ok(false)
 }
 
-/// Commit the current index as a fixup or squash commit
-pub(crate) fn do_fixup_commit<'a>(
+/// Commit each fixup's diff on top of HEAD as a fixup or squash commit
+///
+/// Squash commits carry the target's new message after the subject line, the
+/// way `git commit --squash` does. Returns the ids of the new commits, in the
+/// same order as `fixups`.
+pub(crate) fn do_fixup_commits<'a>(
     repo: &'a Repository,
     head_branch: &'a Branch,
-    commit_to_amend: &'a Commit,
-    squash: bool,
-) -> Result<(), anyhow::Error> {
-    let msg = if squash {
-        format!("squash! {}", commit_to_amend.id())
-    } else {
-        format!("fixup! {}", commit_to_amend.id())
-    };
-
+    fixups: &[Fixup<'a>],
+) -> Result<Vec<Oid>, anyhow::Error> {
     let sig = repo.signature()?;
-    let mut idx = repo.index()?;
-    let tree = repo.find_tree(idx.write_tree()?)?;
-    let head_commit = head_branch.get().peel_to_commit()?;
-    repo.commit(Some("HEAD"), &sig, &sig, &msg, &tree, &[&head_commit])?;
-    Ok(())
+    let mut parent = head_branch.get().peel_to_commit()?;
+    let mut fixup_commits = Vec::with_capacity(fixups.len());
+    for fixup in fixups {
+        let commit_to_amend = &fixup.target;
+        let msg = match &fixup.message {
+            Some(message) => format!("squash! {}\n\n{message}", commit_to_amend.id()),
+            None => format!("fixup! {}", commit_to_amend.id()),
+        };
+
+        let Some(tree) = rebaser::fixed_tree(repo, fixup, &parent.tree()?)? else {
+            bail!(
+                "Unable to commit the changes for {} on top of the others",
+                commit_display(commit_to_amend)
+            );
+        };
+        let oid = repo.commit(Some("HEAD"), &sig, &sig, &msg, &tree, &[&parent])?;
+        parent = repo.find_commit(oid)?;
+        fixup_commits.push(oid);
+    }
+    Ok(fixup_commits)
+}
+
+/// The new message carried by a squash commit from `do_fixup_commits`, if it is one
+pub(crate) fn squash_message(commit: &Commit) -> Option<String> {
+    let message = commit.message()?;
+    if !message.starts_with("squash! ") {
+        return None;
+    }
+    message.split_once("\n\n").map(|(_, body)| body.to_owned())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Commit `content` as the file `name` on top of HEAD, if there is one
+    pub(crate) fn commit_file<'r>(repo: &'r Repository, name: &str, content: &[u8]) -> Commit<'r> {
+        std::fs::write(repo.workdir().unwrap().join(name), content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(name.as_ref()).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("nobody", "nobody@nowhere.com").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let id = repo
+            .commit(
+                Some("HEAD"),
+                &sig,
+                &sig,
+                name,
+                &tree,
+                &parent.iter().collect::<Vec<_>>(),
+            )
+            .unwrap();
+        repo.find_commit(id).unwrap()
+    }
+
+    #[test]
+    fn preview_commit_with_non_utf8_content() {
+        let td = assert_fs::TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+        let commit = commit_file(&repo, "latin1.txt", b"caf\xe9\n");
+        let lines = commit_preview(&repo, &commit, crate::config::DEFAULT_THEME, 80).unwrap();
+        let plain = lines
+            .iter()
+            .map(|l| console::strip_ansi_codes(l).into_owned())
+            .collect::<Vec<_>>();
+        assert!(
+            plain.iter().any(|l| l == "+caf\u{fffd}"),
+            "lines: {:?}",
+            plain
+        );
+    }
+
+    fn staged(repo: &Repository, name: &str) -> String {
+        let index = repo.index().unwrap();
+        let entry = index.get_path(name.as_ref(), 0).unwrap();
+        let blob = repo.find_blob(entry.id).unwrap();
+        String::from_utf8(blob.content().to_owned()).unwrap()
+    }
+
+    /// Choosing hunks one at a time, skipping the first hunk of a file and a whole other file
+    #[test]
+    fn pick_hunks_stages_only_the_chosen_hunks() {
+        let td = assert_fs::TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+        let lines = (1..=20).map(|n| format!("{n}\n")).collect::<String>();
+        commit_file(&repo, "f", lines.as_bytes());
+        commit_file(&repo, "g", b"g\n");
+        let changed = lines
+            .replacen("\n3\n", "\n3\ninserted\n", 1)
+            .replace("18\n", "eighteen\n");
+        std::fs::write(td.path().join("f"), &changed).unwrap();
+        std::fs::write(td.path().join("g"), "changed\n").unwrap();
+
+        let dirty = repo
+            .diff_index_to_workdir(None, Some(&mut diff_opts()))
+            .unwrap();
+        let hunks = DiffHunks::from_diff(&dirty).unwrap();
+        let mut files_asked = Vec::new();
+        let mut hunks_asked = Vec::new();
+        let picked = choose_hunks(
+            &hunks,
+            |path, items| {
+                files_asked.push((path.to_owned(), items.len()));
+                Ok(if path == Path::new("f") { 2 } else { 1 })
+            },
+            |id| {
+                hunks_asked.push(id);
+                Ok(id == (0, 1))
+            },
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(files_asked, [("f".into(), 3), ("g".into(), 2)]);
+        assert_eq!(hunks_asked, [(0, 0), (0, 1)]);
+
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let dry_run = stage(&repo, &head_tree, &picked, true).unwrap();
+        assert_eq!(dry_run.stats().unwrap().files_changed(), 1);
+        assert_eq!(staged(&repo, "f"), lines);
+
+        let staged_diff = stage(&repo, &head_tree, &picked, false).unwrap();
+        assert_eq!(staged_diff.stats().unwrap().files_changed(), 1);
+        assert_eq!(staged(&repo, "f"), lines.replace("18\n", "eighteen\n"));
+        assert_eq!(staged(&repo, "g"), "g\n");
+        // everything that wasn't chosen is still in the working tree, unstaged
+        assert_eq!(
+            std::fs::read_to_string(td.path().join("f")).unwrap(),
+            changed
+        );
+        let unstaged = repo.diff_index_to_workdir(None, None).unwrap();
+        assert_eq!(unstaged.stats().unwrap().files_changed(), 2);
+    }
 }
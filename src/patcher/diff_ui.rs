@@ -26,7 +26,8 @@ pub(crate) fn native_diff(diff: &Diff<'_>, theme: &str) -> Result<Vec<String>, a
     let mut diff_lines = Vec::new();
 
     diff.print(DiffFormat::Patch, |_delta, _hunk, line| {
-        let content = std::str::from_utf8(line.content()).unwrap();
+        // files in other encodings are shown as best we can rather than refused
+        let content = String::from_utf8_lossy(line.content());
         let origin = line.origin();
         match origin {
             '+' | '-' | ' ' => {
@@ -42,7 +43,7 @@ pub(crate) fn native_diff(diff: &Diff<'_>, theme: &str) -> Result<Vec<String>, a
                 diff_lines.push(escaped);
             }
             _ => {
-                let ranges = match h.highlight_line(content, &ss) {
+                let ranges = match h.highlight_line(&content, &ss) {
                     Ok(ranges) => ranges,
                     Err(err) => {
                         inner_err = Some(err);
@@ -74,9 +75,14 @@ pub(crate) fn print_diff_lines(diff_lines: &[String]) -> Result<(), anyhow::Erro
 }
 
 pub(crate) fn print_diffstat(prefix: &str, diff: &Diff<'_>) -> Result<(), anyhow::Error> {
-    let buf = diff.stats()?.to_buf(DiffStatsFormat::FULL, 80)?;
-    let stat = std::str::from_utf8(&buf).context("converting diffstat to utf-8")?;
+    let stat = diffstat(diff, 80)?;
     println!("{prefix} changes:\n{stat}");
 
     Ok(())
 }
+
+pub(crate) fn diffstat(diff: &Diff<'_>, width: usize) -> Result<String, anyhow::Error> {
+    let buf = diff.stats()?.to_buf(DiffStatsFormat::FULL, width)?;
+    let stat = std::str::from_utf8(&buf).context("converting diffstat to utf-8")?;
+    Ok(stat.to_owned())
+}
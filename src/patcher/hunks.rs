@@ -0,0 +1,283 @@
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use git2::Diff;
+use git2::DiffFormat;
+
+/// A diff broken up into files and hunks so that parts of it can be applied
+/// separately
+///
+/// Files without any hunks (new empty files, mode changes, binary files) are
+/// treated as a single hunk so that every part of the diff can be addressed
+/// with a [`HunkId`].
+pub(crate) struct DiffHunks {
+    pub(crate) files: Vec<FileHunks>,
+}
+
+pub(crate) struct FileHunks {
+    pub(crate) path: PathBuf,
+    /// The `diff --git` header, plus the binary patch for binary files
+    header: Vec<u8>,
+    /// The text of each hunk, starting with its `@@` line
+    hunks: Vec<Vec<u8>>,
+}
+
+/// Identifies a hunk by (file index, hunk index)
+pub(crate) type HunkId = (usize, usize);
+
+impl DiffHunks {
+    pub(crate) fn from_diff(diff: &Diff<'_>) -> Result<DiffHunks, anyhow::Error> {
+        let mut files: Vec<FileHunks> = Vec::new();
+        diff.print(DiffFormat::Patch, |delta, _hunk, line| {
+            let content = line.content();
+            match line.origin() {
+                'F' => files.push(FileHunks {
+                    path: delta
+                        .new_file()
+                        .path()
+                        .or_else(|| delta.old_file().path())
+                        .map(|p| p.to_owned())
+                        .unwrap_or_default(),
+                    header: content.to_vec(),
+                    hunks: Vec::new(),
+                }),
+                'B' => {
+                    if let Some(file) = files.last_mut() {
+                        file.header.extend_from_slice(content)
+                    }
+                }
+                'H' => {
+                    if let Some(file) = files.last_mut() {
+                        file.hunks.push(content.to_vec())
+                    }
+                }
+                origin => {
+                    if let Some(hunk) = files.last_mut().and_then(|f| f.hunks.last_mut()) {
+                        if let '+' | '-' | ' ' = origin {
+                            hunk.push(origin as u8);
+                        }
+                        hunk.extend_from_slice(content);
+                    }
+                }
+            }
+            true
+        })?;
+        Ok(DiffHunks { files })
+    }
+
+    /// Every hunk in the file at index `f`
+    pub(crate) fn file_hunk_ids(&self, f: usize) -> impl Iterator<Item = HunkId> {
+        (0..self.files[f].hunks.len().max(1)).map(move |h| (f, h))
+    }
+
+    /// A short description of a hunk, like `src/lib.rs @@ -1,3 +1,4 @@`
+    pub(crate) fn describe(&self, (f, h): HunkId) -> String {
+        let file = &self.files[f];
+        let path = file.path.display();
+        match file.hunks.get(h) {
+            Some(hunk) => {
+                let header = hunk.split(|b| *b == b'\n').next().unwrap_or_default();
+                format!("{path} {}", String::from_utf8_lossy(header))
+            }
+            None => path.to_string(),
+        }
+    }
+
+    /// Build a diff containing only the selected hunks
+    ///
+    /// Returns `None` if nothing is selected.
+    pub(crate) fn select(
+        &self,
+        mut selected: impl FnMut(HunkId) -> bool,
+    ) -> Result<Option<Diff<'static>>, anyhow::Error> {
+        let mut patch = Vec::new();
+        for (f, file) in self.files.iter().enumerate() {
+            if file.hunks.is_empty() {
+                if selected((f, 0)) {
+                    patch.extend_from_slice(&file.header);
+                }
+                continue;
+            }
+            // libgit2 places hunks by their new start, which moves by the
+            // number of lines that skipped hunks before it would have added
+            let mut skipped_offset = 0;
+            let mut hunks = Vec::new();
+            for (h, hunk) in file.hunks.iter().enumerate() {
+                let header = HunkHeader::parse(hunk)?;
+                if !selected((f, h)) {
+                    skipped_offset += header.new_lines as i64 - header.old_lines as i64;
+                    continue;
+                }
+                let new_start = header.new_start as i64 - skipped_offset;
+                hunks.extend_from_slice(
+                    format!(
+                        "@@ -{},{} +{},{} @@",
+                        header.old_start, header.old_lines, new_start, header.new_lines
+                    )
+                    .as_bytes(),
+                );
+                hunks.extend_from_slice(&hunk[header.len..]);
+            }
+            if !hunks.is_empty() {
+                patch.extend_from_slice(&file.header);
+                patch.extend_from_slice(&hunks);
+            }
+        }
+        if patch.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(Diff::from_buffer(&patch)?))
+        }
+    }
+}
+
+/// The line numbers from the start of a hunk, `@@ -old_start,old_lines +new_start,new_lines @@`
+struct HunkHeader {
+    old_start: u32,
+    old_lines: u32,
+    new_start: u32,
+    new_lines: u32,
+    /// The length of the line numbers part, up to and including the second `@@`
+    len: usize,
+}
+
+impl HunkHeader {
+    fn parse(hunk: &[u8]) -> Result<HunkHeader, anyhow::Error> {
+        let line = hunk.split(|b| *b == b'\n').next().unwrap_or_default();
+        let invalid = || anyhow!("invalid hunk header: {}", String::from_utf8_lossy(line));
+        let rest = line.strip_prefix(b"@@ -").ok_or_else(invalid)?;
+        // the function context after the second `@@` can be in any encoding,
+        // so only the line numbers before it are decoded
+        let end = rest
+            .windows(" @@".len())
+            .position(|w| w == b" @@")
+            .ok_or_else(invalid)?;
+        let ranges = std::str::from_utf8(&rest[..end]).map_err(|_| invalid())?;
+        let (old, new) = ranges.split_once(" +").ok_or_else(invalid)?;
+        // the line count is left out when it is 1
+        let range = |r: &str| -> Option<(u32, u32)> {
+            match r.split_once(',') {
+                Some((start, lines)) => Some((start.parse().ok()?, lines.parse().ok()?)),
+                None => Some((r.parse().ok()?, 1)),
+            }
+        };
+        let (old_start, old_lines) = range(old).ok_or_else(invalid)?;
+        let (new_start, new_lines) = range(new).ok_or_else(invalid)?;
+        Ok(HunkHeader {
+            old_start,
+            old_lines,
+            new_start,
+            new_lines,
+            len: "@@ -".len() + end + " @@".len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(lines: impl Iterator<Item = String>) -> String {
+        lines.map(|l| format!("{l}\n")).collect()
+    }
+
+    /// A diff of a 20 line file with a line inserted after line 3 and line 18 changed
+    fn two_hunk_diff() -> (String, String, Diff<'static>) {
+        let old = numbered((1..=20).map(|n| n.to_string()));
+        let new = numbered((1..=20).flat_map(|n| match n {
+            3 => vec!["3".to_owned(), "inserted".to_owned()],
+            18 => vec!["eighteen".to_owned()],
+            n => vec![n.to_string()],
+        }));
+        let patch = git2::Patch::from_buffers(
+            old.as_bytes(),
+            Some("f".as_ref()),
+            new.as_bytes(),
+            Some("f".as_ref()),
+            None,
+        )
+        .unwrap()
+        .to_buf()
+        .unwrap();
+        let diff = Diff::from_buffer(&patch).unwrap();
+        (old, new, diff)
+    }
+
+    fn apply(old: &str, diff: &Diff<'_>) -> String {
+        let td = assert_fs::TempDir::new().unwrap();
+        let repo = git2::Repository::init(td.path()).unwrap();
+        let blob = repo.blob(old.as_bytes()).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("f", blob, 0o100644).unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let mut applied = repo.apply_to_tree(&tree, diff, None).unwrap();
+        let tree = repo
+            .find_tree(applied.write_tree_to(&repo).unwrap())
+            .unwrap();
+        let entry = tree.get_name("f").unwrap();
+        let content = repo.find_blob(entry.id()).unwrap().content().to_vec();
+        String::from_utf8(content).unwrap()
+    }
+
+    #[test]
+    fn select_after_skipped_hunk() {
+        let (old, new, diff) = two_hunk_diff();
+        let hunks = DiffHunks::from_diff(&diff).unwrap();
+        assert_eq!(hunks.file_hunk_ids(0).count(), 2);
+
+        let second = hunks.select(|id| id == (0, 1)).unwrap().unwrap();
+        let expected = old.replace("18\n", "eighteen\n");
+        assert_eq!(apply(&old, &second), expected);
+
+        let first = hunks.select(|id| id == (0, 0)).unwrap().unwrap();
+        let expected = old.replacen("\n3\n", "\n3\ninserted\n", 1);
+        assert_eq!(apply(&old, &first), expected);
+
+        let both = hunks.select(|_| true).unwrap().unwrap();
+        assert_eq!(apply(&old, &both), new);
+        assert!(hunks.select(|_| false).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_hunk_header() {
+        let header = HunkHeader::parse(b"@@ -3,0 +4,2 @@ fn main() {\n").unwrap();
+        assert_eq!(
+            (
+                header.old_start,
+                header.old_lines,
+                header.new_start,
+                header.new_lines
+            ),
+            (3, 0, 4, 2)
+        );
+        assert_eq!(header.len, "@@ -3,0 +4,2 @@".len());
+        let header = HunkHeader::parse(b"@@ -7 +7 @@\n").unwrap();
+        assert_eq!(
+            (
+                header.old_start,
+                header.old_lines,
+                header.new_start,
+                header.new_lines
+            ),
+            (7, 1, 7, 1)
+        );
+    }
+
+    #[test]
+    fn parse_hunk_header_with_any_context() {
+        // a multibyte char straddling the first 100 bytes
+        let mut hunk = format!("@@ -1,2 +1,3 @@ {}é…\n", "x".repeat(80)).into_bytes();
+        hunk.extend_from_slice(b" context\n+added\n");
+        let header = HunkHeader::parse(&hunk).unwrap();
+        assert_eq!((header.new_start, header.new_lines), (1, 3));
+        assert_eq!(header.len, "@@ -1,2 +1,3 @@".len());
+
+        let header = HunkHeader::parse(b"@@ -5,2 +5,2 @@ caf\xe9\n-old\n+new\n").unwrap();
+        assert_eq!((header.old_start, header.old_lines), (5, 2));
+
+        let err = HunkHeader::parse(b"@@ -x +1 @@\n-old\n+new\n")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "invalid hunk header: @@ -x +1 @@");
+    }
+}
@@ -1,106 +1,593 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 
 use anyhow::Context as _;
 use anyhow::{anyhow, bail};
+use git2::build::CheckoutBuilder;
 use git2::AnnotatedCommit;
 use git2::Branch;
 use git2::Commit;
 use git2::Diff;
+use git2::Index;
 use git2::Oid;
-use git2::{Rebase, Repository};
+use git2::Reference;
+use git2::Signature;
+use git2::Tree;
+use git2::{Rebase, RebaseOptions, Repository};
 
 use crate::commit_display;
+use crate::signer;
+use crate::signer::Signer;
+use crate::worktrees;
 
-pub(crate) fn do_rebase(
-    repo: &Repository,
+/// Changes to apply to a single commit during the rebase
+pub(crate) struct Fixup<'a> {
+    pub(crate) target: Commit<'a>,
+    pub(crate) diff: Diff<'a>,
+    /// The tree that `diff` applies to, HEAD's when the changes were staged
+    pub(crate) base: Tree<'a>,
+    /// A new message for the target, if it is being squashed instead of fixed up
+    pub(crate) message: Option<String>,
+}
+
+/// Which author and committer rewritten commits get
+///
+/// By default amended commits keep their author and every rewritten commit
+/// gets the current user as its committer, like `git rebase`.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Identities {
+    /// Keep the original committer and commit date of commits that are only moved
+    pub(crate) keep_committer: bool,
+    /// Use each rewritten commit's author date as its commit date
+    pub(crate) committer_date_is_author_date: bool,
+    /// Make the current user the author of amended commits
+    pub(crate) reset_author: bool,
+}
+
+impl Identities {
+    /// The author and committer for the rewritten `commit`
+    fn signatures(
+        &self,
+        repo: &Repository,
+        commit: &Commit,
+        amended: bool,
+    ) -> Result<(Signature<'static>, Signature<'static>), anyhow::Error> {
+        let author = if self.reset_author && amended {
+            repo.signature()?.to_owned()
+        } else {
+            commit.author().to_owned()
+        };
+        let committer = if self.keep_committer && !amended {
+            commit.committer().to_owned()
+        } else {
+            repo.signature()?.to_owned()
+        };
+        if !self.committer_date_is_author_date {
+            return Ok((author, committer));
+        }
+        let committer = Signature::new(
+            &String::from_utf8_lossy(committer.name_bytes()),
+            &String::from_utf8_lossy(committer.email_bytes()),
+            &author.when(),
+        )?;
+        Ok((author, committer))
+    }
+}
+
+/// Rebase `branch` from the parent of the oldest fixup target, applying each
+/// fixup's diff as its target is reached
+///
+/// `fixup_commits` are the commits created by `patcher::do_fixup_commits`,
+/// they are dropped from the rebased branch.
+///
+/// A conflicting pick leaves the rebase in progress with the conflict in the
+/// working tree and returns it. Any other failure aborts the rebase.
+///
+/// A rebase can't pick a root commit, so if the oldest target is one it is
+/// amended up front and the rest of the branch is rebased onto it.
+pub(crate) fn do_rebase<'a>(
+    repo: &'a Repository,
     branch: &Branch,
-    commit_to_amend: &Commit,
-    diff: &Diff,
-) -> Result<(), anyhow::Error> {
-    let first_parent = repo.find_annotated_commit(commit_parent(commit_to_amend)?.id())?;
+    fixups: &[Fixup],
+    fixup_commits: &[Oid],
+    identities: Identities,
+    refs: &RefUpdates,
+) -> Result<Rebased<'a>, anyhow::Error> {
+    let oldest_target = oldest_target(repo, fixups)?;
+    let (upstream, onto) = match oldest_target.parents().next() {
+        Some(parent) => (repo.find_annotated_commit(parent.id())?, None),
+        None => {
+            let root = amend_root(repo, oldest_target, fixups, identities)?;
+            (
+                repo.find_annotated_commit(oldest_target.id())?,
+                Some(repo.find_annotated_commit(root)?),
+            )
+        }
+    };
     let branch_commit = repo.reference_to_annotated_commit(branch.get())?;
-    let fixup_commit = branch.get().peel_to_commit()?;
-    let fixup_message = fixup_commit.message();
+    let skipped: HashSet<Oid> = fixup_commits.iter().copied().collect();
 
     let rebase = &mut repo
-        .rebase(Some(&branch_commit), Some(&first_parent), None, None)
+        .rebase(
+            Some(&branch_commit),
+            Some(&upstream),
+            onto.as_ref(),
+            Some(&mut rebase_options()),
+        )
         .context("starting rebase")?;
 
-    let mut branches = RepoBranches::for_repo(repo)?;
-
-    if let Err(e) = apply_diff_in_rebase(repo, rebase, diff, &mut branches) {
-        print_help_and_abort_rebase(rebase, &first_parent).context("aborting rebase")?;
-        return Err(e);
+    let mut branches = RepoBranches::for_repo(repo, refs)?;
+    if let Some(root) = &onto {
+        record_rewritten(repo, oldest_target.id(), root.id())?;
+        for b in branches.retarget_branches(repo, oldest_target.id(), root.id(), rebase)? {
+            println!("{}", b);
+        }
     }
 
-    match do_rebase_inner(repo, rebase, fixup_message, branches) {
-        Ok(_) => {
-            rebase.finish(None)?;
-            Ok(())
-        }
+    match do_rebase_inner(repo, rebase, fixups, &skipped, branches, identities) {
+        Ok(Some(conflict)) => Ok(Rebased::Stopped(conflict)),
+        Ok(None) => Ok(Rebased::Finished(finish_rebase(repo, rebase)?)),
         Err(e) => {
-            print_help_and_abort_rebase(rebase, &first_parent).context("aborting rebase")?;
+            print_help_and_abort_rebase(rebase, onto.is_none().then_some(&upstream))
+                .context("aborting rebase")?;
             Err(e)
         }
     }
 }
 
+/// Options for starting or opening a rebase
+fn rebase_options() -> RebaseOptions<'static> {
+    let mut opts = RebaseOptions::new();
+    // Notes are copied by `notes::copy` after the rebase, which unlike libgit2
+    // handles every `notes.rewriteRef`, so point libgit2 at a ref with no notes
+    opts.rewrite_notes_ref("refs/notes/instafix-no-notes");
+    opts
+}
+
+/// Create the root commit `root` with its fixup applied, without a rebase
+fn amend_root(
+    repo: &Repository,
+    root: &Commit,
+    fixups: &[Fixup],
+    identities: Identities,
+) -> Result<Oid, anyhow::Error> {
+    let fixup = fixups
+        .iter()
+        .find(|f| f.target.id() == root.id())
+        .ok_or_else(|| anyhow!("No fixup for root commit {}", commit_display(root)))?;
+    let Some(tree) = fixed_tree(repo, fixup, &root.tree()?)? else {
+        bail!(
+            "Applying your changes to {} conflicts with later commits",
+            commit_display(root)
+        );
+    };
+    let (author, committer) = identities.signatures(repo, root, true)?;
+    let message = match &fixup.message {
+        Some(message) => message,
+        None => root.message().unwrap_or_default(),
+    };
+    Ok(repo.commit(None, &author, &committer, message, &tree, &[])?)
+}
+
+/// Carry on with a rebase that `do_rebase` stopped at a conflict
+///
+/// The conflicted commit is committed with whatever is in the index, which
+/// must not have any conflicts left.
+pub(crate) fn continue_rebase<'a>(
+    repo: &'a Repository,
+    fixups: &[Fixup],
+    fixup_commits: &[Oid],
+    mut branches: RepoBranches<'a>,
+    identities: Identities,
+    fixup_applied: bool,
+) -> Result<Rebased<'a>, anyhow::Error> {
+    if repo.index()?.has_conflicts() {
+        bail!("There are still unresolved conflicts, `git add` the resolved files first");
+    }
+    let rebase = &mut repo
+        .open_rebase(Some(&mut rebase_options()))
+        .context("opening stopped rebase")?;
+    let skipped: HashSet<Oid> = fixup_commits.iter().copied().collect();
+    let current = rebase
+        .operation_current()
+        .and_then(|i| rebase.nth(i))
+        .map(|op| op.id())
+        .ok_or_else(|| anyhow!("The rebase has no commit in progress"))?;
+    let commit = repo.find_commit(current)?;
+    if !skipped.contains(&commit.id()) {
+        let picked = pick_commit(
+            repo,
+            rebase,
+            &commit,
+            fixups,
+            &mut branches,
+            identities,
+            fixup_applied,
+        )?;
+        if !picked {
+            return Ok(Rebased::Stopped(Conflict {
+                commit: commit.id(),
+                fixup_applied: true,
+                branches,
+            }));
+        }
+    }
+    match do_rebase_inner(repo, rebase, fixups, &skipped, branches, identities)? {
+        Some(conflict) => Ok(Rebased::Stopped(conflict)),
+        None => Ok(Rebased::Finished(finish_rebase(repo, rebase)?)),
+    }
+}
+
+/// The file where libgit2 records each commit that a rebase rewrites, as `<original> <rewritten>` lines
+fn rewritten_path(repo: &Repository) -> std::path::PathBuf {
+    repo.path().join("rebase-merge").join("rewritten")
+}
+
+/// Record a commit that was rewritten outside of the rebase, along with the ones it picks
+fn record_rewritten(repo: &Repository, original: Oid, rewritten: Oid) -> Result<(), anyhow::Error> {
+    use std::io::Write as _;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(rewritten_path(repo))?;
+    writeln!(file, "{original} {rewritten}")?;
+    Ok(())
+}
+
+/// Finish the rebase, returning every (original, rewritten) commit that it picked
+fn finish_rebase(repo: &Repository, rebase: &mut Rebase) -> Result<Vec<(Oid, Oid)>, anyhow::Error> {
+    let recorded = std::fs::read_to_string(rewritten_path(repo)).unwrap_or_default();
+    let rewritten = recorded
+        .lines()
+        .map(|line| match line.split_once(' ') {
+            Some((original, rewritten)) => {
+                Ok((Oid::from_str(original)?, Oid::from_str(rewritten)?))
+            }
+            None => bail!("unexpected line in rewritten commits: {line:?}"),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    rebase.finish(None)?;
+    Ok(rewritten)
+}
+
+/// Throw away a rebase that `do_rebase` stopped at a conflict, putting HEAD back where it was
+pub(crate) fn abort_rebase(repo: &Repository) -> Result<(), anyhow::Error> {
+    let mut rebase = repo.open_rebase(None).context("opening stopped rebase")?;
+    rebase.abort()?;
+    Ok(())
+}
+
+/// How a rebase that was started or continued ended
+pub(crate) enum Rebased<'a> {
+    /// Every commit was picked, these are (original, rewritten) for each of them
+    Finished(Vec<(Oid, Oid)>),
+    Stopped(Conflict<'a>),
+}
+
+/// A rebase that was left in progress because picking a commit conflicted
+pub(crate) struct Conflict<'a> {
+    pub(crate) commit: Oid,
+    /// Whether the conflict came from merging the commit's fixup, so it is already applied
+    pub(crate) fixup_applied: bool,
+    /// The branches that still need to be moved once the rebase carries on
+    pub(crate) branches: RepoBranches<'a>,
+}
+
+/// Commits produced by replaying a branch with fixups applied
+pub(crate) struct Rewrite {
+    /// Every commit that was replayed, oldest first
+    pub(crate) commits: Vec<RewrittenCommit>,
+    /// The commit that couldn't be replayed because of a conflict
+    ///
+    /// Nothing after it is replayed, so it is not in `commits`.
+    pub(crate) conflict: Option<Oid>,
+}
+
+pub(crate) struct RewrittenCommit {
+    pub(crate) original: Oid,
+    pub(crate) rewritten: Oid,
+    /// Whether a fixup was applied to this commit, instead of it just being moved
+    pub(crate) amended: bool,
+}
+
+/// Replay `branch` from the parents of the oldest fixup targets, without
+/// touching HEAD, the index, the working tree or any refs
+///
+/// Merge commits are kept, with their rewritten parents, like `git rebase
+/// --rebase-merges`. Commits that weren't changed and don't descend from a
+/// changed commit, like commits merged in from another branch, are left as
+/// they are.
+///
+/// The rewritten commits are written to the object database, signed with
+/// `signer` if there is one, but nothing points at them.
+pub(crate) fn rewrite_in_memory(
+    repo: &Repository,
+    branch: &Branch,
+    fixups: &[Fixup],
+    signer: Option<&Signer>,
+    identities: Identities,
+) -> Result<Rewrite, anyhow::Error> {
+    let mut rewritten: HashMap<Oid, Oid> = HashMap::new();
+    let mut commits = Vec::new();
+    for oid in range_walk(repo, branch, fixups)? {
+        let commit = repo.find_commit(oid?)?;
+        let fixup = fixups.iter().find(|f| f.target.id() == commit.id());
+        let parents = commit
+            .parent_ids()
+            .map(|p| rewritten.get(&p).copied().unwrap_or(p))
+            .collect::<Vec<_>>();
+        if fixup.is_none() && commit.parent_ids().eq(parents.iter().copied()) {
+            continue;
+        }
+        let Some(mut tree) = replay_tree(repo, &commit, &parents)? else {
+            return Ok(Rewrite {
+                commits,
+                conflict: Some(commit.id()),
+            });
+        };
+        if let Some(fixup) = fixup {
+            let Some(fixed) = fixed_tree(repo, fixup, &tree)? else {
+                return Ok(Rewrite {
+                    commits,
+                    conflict: Some(commit.id()),
+                });
+            };
+            tree = fixed;
+        }
+        let parents = parents
+            .iter()
+            .map(|p| repo.find_commit(*p))
+            .collect::<Result<Vec<_>, _>>()?;
+        let message = match fixup.and_then(|f| f.message.as_deref()) {
+            Some(message) => message,
+            None => commit.message().unwrap_or_default(),
+        };
+        let (author, committer) = identities.signatures(repo, &commit, fixup.is_some())?;
+        let new_id = signer::commit(
+            repo,
+            signer,
+            &author,
+            &committer,
+            message,
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )?;
+        rewritten.insert(commit.id(), new_id);
+        commits.push(RewrittenCommit {
+            original: commit.id(),
+            rewritten: new_id,
+            amended: fixup.is_some(),
+        });
+    }
+    Ok(Rewrite {
+        commits,
+        conflict: None,
+    })
+}
+
+/// `tree` with the changes from `fixup` merged into it
+///
+/// Returns `None` if the change conflicts with `tree`, see `merge_fixup`.
+pub(crate) fn fixed_tree<'r>(
+    repo: &'r Repository,
+    fixup: &Fixup,
+    tree: &Tree<'r>,
+) -> Result<Option<Tree<'r>>, anyhow::Error> {
+    let mut merged = merge_fixup(repo, fixup, tree)?;
+    if merged.has_conflicts() {
+        return Ok(None);
+    }
+    Ok(Some(repo.find_tree(merged.write_tree_to(repo)?)?))
+}
+
+/// Merge the changes from `fixup` into `tree`, possibly with conflicts
+///
+/// The fixup's diff is made against `fixup.base`, usually HEAD, and lines may
+/// have moved between `tree` and HEAD. So rather than patching `tree` at HEAD's
+/// line numbers, the diff is applied to the base and that change is merged
+/// into `tree`.
+fn merge_fixup(repo: &Repository, fixup: &Fixup, tree: &Tree) -> Result<Index, anyhow::Error> {
+    let mut patched = repo
+        .apply_to_tree(&fixup.base, &fixup.diff, None)
+        .context("applying changes to the tree they were made against")?;
+    let patched = repo.find_tree(patched.write_tree_to(repo)?)?;
+    Ok(repo.merge_trees(&fixup.base, tree, &patched, None)?)
+}
+
+/// The tree that `commit` has when it is replayed onto `parents`
+///
+/// `parents` are the commit's original parents with any that have been
+/// rewritten replaced. A merge where only one parent was rewritten is replayed
+/// relative to that parent so that its original conflict resolution is kept,
+/// if both were rewritten the parents are merged again. Returns `None` if
+/// replaying conflicts.
+fn replay_tree<'r>(
+    repo: &'r Repository,
+    commit: &Commit<'r>,
+    parents: &[Oid],
+) -> Result<Option<Tree<'r>>, anyhow::Error> {
+    let changed = commit
+        .parent_ids()
+        .zip(parents)
+        .enumerate()
+        .filter(|(_, (original, new))| original != *new)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let mut index = match (&changed[..], parents) {
+        ([], _) => return Ok(Some(commit.tree()?)),
+        (&[i], _) => {
+            let onto = repo.find_commit(parents[i])?;
+            let mainline = if parents.len() > 1 { i as u32 + 1 } else { 0 };
+            repo.cherrypick_commit(commit, &onto, mainline, None)?
+        }
+        (_, [ours, theirs]) => {
+            repo.merge_commits(&repo.find_commit(*ours)?, &repo.find_commit(*theirs)?, None)?
+        }
+        _ => bail!(
+            "Unable to recreate octopus merge {} with more than one rewritten parent",
+            commit_display(commit)
+        ),
+    };
+    if index.has_conflicts() {
+        return Ok(None);
+    }
+    Ok(Some(repo.find_tree(index.write_tree_to(repo)?)?))
+}
+
+/// Walk every commit that might need to be rewritten to apply `fixups` to `branch`, oldest first
+fn range_walk<'r>(
+    repo: &'r Repository,
+    branch: &Branch,
+    fixups: &[Fixup],
+) -> Result<git2::Revwalk<'r>, anyhow::Error> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    walk.push(branch.get().peel_to_commit()?.id())?;
+    for target in fixups.iter().map(|f| &f.target) {
+        let is_oldest = fixups.iter().all(|other| {
+            other.target.id() == target.id()
+                || !repo
+                    .graph_descendant_of(target.id(), other.target.id())
+                    .unwrap_or(false)
+        });
+        if is_oldest {
+            for parent in target.parent_ids() {
+                walk.hide(parent)?;
+            }
+        }
+    }
+    Ok(walk)
+}
+
+/// Whether any of the commits that would be rewritten are merges, which `do_rebase` can't keep
+pub(crate) fn range_has_merges(
+    repo: &Repository,
+    branch: &Branch,
+    fixups: &[Fixup],
+) -> Result<bool, anyhow::Error> {
+    for oid in range_walk(repo, branch, fixups)? {
+        if repo.find_commit(oid?)?.parent_count() > 1 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Move every branch, and every ref matching `refs.globs`, pointing at a
+/// rewritten commit to its replacement, all at once
+///
+/// HEAD is moved along with its branch, or directly if it is detached. The
+/// index and working tree are left alone, they already contain the changes
+/// that were applied, but other worktrees with a moved branch checked out are
+/// updated. Returns every moved ref other than HEAD's branch.
+pub(crate) fn update_refs(
+    repo: &Repository,
+    rewrite: &Rewrite,
+    refs: &RefUpdates,
+) -> Result<Vec<RetargetedBranch>, anyhow::Error> {
+    let head = repo.head()?;
+    let moved = RepoBranches::for_repo(repo, refs)?.rewritten(rewrite)?;
+    let sig = repo.signature()?;
+    let mut tx = repo.transaction()?;
+    let mut historical = vec![];
+    for branch in moved {
+        let refname = branch.name.as_str();
+        tx.lock_ref(refname)?;
+        if head.name() == Some(refname) {
+            tx.set_target(refname, branch.to, Some(&sig), "git-instafix")?;
+        } else {
+            let msg = "git-instafix retarget historical branch";
+            tx.set_target(refname, branch.to, Some(&sig), msg)?;
+            historical.push(branch);
+        }
+    }
+    if repo.head_detached()? {
+        let head_id = head.peel_to_commit()?.id();
+        if let Some(commit) = rewrite.commits.iter().find(|c| c.original == head_id) {
+            tx.lock_ref("HEAD")?;
+            tx.set_target("HEAD", commit.rewritten, Some(&sig), "git-instafix")?;
+        }
+    }
+    tx.commit().context("updating branches")?;
+    for branch in &historical {
+        worktrees::sync(repo, &branch.name, branch.from, branch.to)?;
+    }
+    Ok(historical)
+}
+
+/// `first_parent` is the parent of the oldest target, or `None` if it is a root commit
 pub(crate) fn print_help_and_abort_rebase(
     rebase: &mut Rebase,
-    first_parent: &AnnotatedCommit,
+    first_parent: Option<&AnnotatedCommit>,
 ) -> Result<(), git2::Error> {
     eprintln!("Aborting rebase, your changes are in the head commit.");
     eprintln!("You can apply it manually via:");
-    eprintln!(
-        "    git rebase --interactive --autosquash {}~",
-        first_parent.id()
-    );
+    match first_parent {
+        Some(first_parent) => eprintln!(
+            "    git rebase --interactive --autosquash {}",
+            first_parent.id()
+        ),
+        None => eprintln!("    git rebase --interactive --autosquash --root"),
+    }
     rebase.abort()?;
     Ok(())
 }
 
+/// Apply a fixup to the commit that the rebase has just picked
+///
+/// Returns `None` if the fixup conflicts with the commit, leaving the conflict
+/// in the index and working tree to be resolved.
 pub(crate) fn apply_diff_in_rebase(
     repo: &Repository,
     rebase: &mut Rebase,
-    diff: &Diff,
-    branches: &mut RepoBranches,
-) -> Result<(), anyhow::Error> {
-    match rebase.next() {
-        Some(ref res) => {
-            let op = res.as_ref().map_err(|e| anyhow!("No commit: {}", e))?;
-            let target_commit = repo.find_commit(op.id())?;
-            repo.apply(diff, git2::ApplyLocation::Both, None)?;
-            let mut idx = repo.index()?;
-            let oid = idx.write_tree()?;
-            let tree = repo.find_tree(oid)?;
-
-            // TODO: Support squash amends
-
-            let rewrit_id = target_commit.amend(None, None, None, None, None, Some(&tree))?;
-            let rewrit_object = repo.find_object(rewrit_id, None)?;
-            let rewrit_commit_id = repo.find_commit(rewrit_object.id())?.id();
-            let retargeted =
-                branches.retarget_branches(target_commit.id(), rewrit_commit_id, rebase)?;
-            for b in retargeted {
-                println!("{}", b);
-            }
+    fixup: &Fixup,
+    identities: Identities,
+) -> Result<Option<Oid>, anyhow::Error> {
+    let mut index = repo.index()?;
+    let picked = repo.find_tree(index.write_tree()?)?;
+    let merged = merge_fixup(repo, fixup, &picked)?;
+    // copy the merge into the repository's index, conflicting stages and all
+    index.clear()?;
+    for entry in merged.iter() {
+        index.add(&entry)?;
+    }
+    index.write()?;
+    let mut checkout = CheckoutBuilder::new();
+    let target = commit_display(&fixup.target);
+    checkout
+        .force()
+        .allow_conflicts(true)
+        .conflict_style_merge(true)
+        .our_label(&target)
+        .their_label("your changes");
+    repo.checkout_index(Some(&mut index), Some(&mut checkout))
+        .context("applying changes to target commit")?;
+    if index.has_conflicts() {
+        return Ok(None);
+    }
+    Ok(Some(commit_fixup(rebase, repo, fixup, identities)?))
+}
 
-            repo.reset(&rewrit_object, git2::ResetType::Soft, None)?;
-        }
-        None => bail!("Unable to start rebase: no first step in rebase"),
-    };
-    Ok(())
+/// Commit the index as the rebase's current pick, with the fixup's identities and message
+fn commit_fixup(
+    rebase: &mut Rebase,
+    repo: &Repository,
+    fixup: &Fixup,
+    identities: Identities,
+) -> Result<Oid, anyhow::Error> {
+    let (author, committer) = identities.signatures(repo, &fixup.target, true)?;
+    Ok(rebase.commit(Some(&author), &committer, fixup.message.as_deref())?)
 }
 
 /// Do a rebase, pulling all intermediate branches along the way
-pub(crate) fn do_rebase_inner(
+pub(crate) fn do_rebase_inner<'a>(
     repo: &Repository,
     rebase: &mut Rebase,
-    fixup_message: Option<&str>,
-    mut branches: RepoBranches,
-) -> Result<(), anyhow::Error> {
-    let sig = repo.signature()?;
-
+    fixups: &[Fixup],
+    skipped: &HashSet<Oid>,
+    mut branches: RepoBranches<'a>,
+    identities: Identities,
+) -> Result<Option<Conflict<'a>>, anyhow::Error> {
     while let Some(ref res) = rebase.next() {
         use git2::RebaseOperationType::*;
 
@@ -108,12 +595,28 @@ pub(crate) fn do_rebase_inner(
         match op.kind() {
             Some(Pick) => {
                 let commit = repo.find_commit(op.id())?;
-                let message = commit.message();
-                if message.is_some() && message != fixup_message {
-                    let new_id = rebase.commit(None, &sig, None)?;
-                    let retargeted = branches.retarget_branches(commit.id(), new_id, rebase)?;
-                    for b in retargeted {
-                        println!("{}", b);
+                if !skipped.contains(&commit.id()) {
+                    if repo.index()?.has_conflicts() {
+                        return Ok(Some(Conflict {
+                            commit: commit.id(),
+                            fixup_applied: false,
+                            branches,
+                        }));
+                    }
+                    if !pick_commit(
+                        repo,
+                        rebase,
+                        &commit,
+                        fixups,
+                        &mut branches,
+                        identities,
+                        false,
+                    )? {
+                        return Ok(Some(Conflict {
+                            commit: commit.id(),
+                            fixup_applied: true,
+                            branches,
+                        }));
                     }
                 }
             }
@@ -125,70 +628,208 @@ pub(crate) fn do_rebase_inner(
         }
     }
 
-    Ok(())
+    Ok(None)
+}
+
+/// Commit the rebase's current pick, applying its fixup if it has one
+///
+/// Returns false, without committing, if the fixup conflicts. Pass
+/// `fixup_applied` once such a conflict has been resolved.
+fn pick_commit(
+    repo: &Repository,
+    rebase: &mut Rebase,
+    commit: &Commit,
+    fixups: &[Fixup],
+    branches: &mut RepoBranches,
+    identities: Identities,
+    fixup_applied: bool,
+) -> Result<bool, anyhow::Error> {
+    let new_id = match fixups.iter().find(|f| f.target.id() == commit.id()) {
+        Some(fixup) if fixup_applied => commit_fixup(rebase, repo, fixup, identities)?,
+        Some(fixup) => match apply_diff_in_rebase(repo, rebase, fixup, identities)? {
+            Some(new_id) => new_id,
+            None => return Ok(false),
+        },
+        None => {
+            let (author, committer) = identities.signatures(repo, commit, false)?;
+            rebase.commit(Some(&author), &committer, None)?
+        }
+    };
+    let retargeted = branches.retarget_branches(repo, commit.id(), new_id, rebase)?;
+    for b in retargeted {
+        println!("{}", b);
+    }
+    Ok(true)
+}
+
+/// Which refs, besides HEAD's branch, follow the commits they point at
+pub(crate) struct RefUpdates {
+    /// Globs of refs to move as well as local branches, from `--update-refs`
+    pub(crate) globs: Vec<String>,
+    /// Branches that stay where they are because they are checked out in another worktree
+    pub(crate) skipped: Vec<String>,
 }
 
-pub(crate) struct RepoBranches<'a>(HashMap<Oid, Vec<Branch<'a>>>);
+/// Local branches, and any other refs that should move with them, by the commit they point at
+pub(crate) struct RepoBranches<'a>(HashMap<Oid, Vec<Reference<'a>>>);
 
+/// A branch or other ref that was moved to a rewritten commit
 pub(crate) struct RetargetedBranch {
+    /// The full name of the ref, like `refs/heads/main`
     pub(crate) name: String,
     pub(crate) from: Oid,
     pub(crate) to: Oid,
 }
 
+impl RetargetedBranch {
+    /// `branch <name>` for branches, `ref <full name>` for anything else
+    pub(crate) fn label(&self) -> String {
+        match self.name.strip_prefix("refs/heads/") {
+            Some(branch) => format!("branch {branch}"),
+            None => format!("ref {}", self.name),
+        }
+    }
+}
+
 impl std::fmt::Display for RetargetedBranch {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let from = &self.from.to_string()[..15];
         let to = &self.to.to_string()[..15];
-        let name = &self.name;
-        f.write_fmt(format_args!("updated branch {name}: {from} -> {to}"))
+        let label = self.label();
+        f.write_fmt(format_args!("updated {label}: {from} -> {to}"))
     }
 }
 
 impl<'a> RepoBranches<'a> {
-    pub(crate) fn for_repo(repo: &'a Repository) -> Result<RepoBranches<'a>, anyhow::Error> {
-        let mut branches: HashMap<Oid, Vec<Branch>> = HashMap::new();
+    /// Every local branch that isn't skipped, plus the refs matching the globs that point directly at a commit
+    pub(crate) fn for_repo(
+        repo: &'a Repository,
+        updates: &RefUpdates,
+    ) -> Result<RepoBranches<'a>, anyhow::Error> {
+        let mut refs: HashMap<Oid, Vec<Reference>> = HashMap::new();
         for (branch, _type) in repo.branches(Some(git2::BranchType::Local))?.flatten() {
-            let oid = branch.get().peel_to_commit()?.id();
-            branches.entry(oid).or_default().push(branch);
+            let reference = branch.into_reference();
+            if updates
+                .skipped
+                .iter()
+                .any(|s| Some(s.as_str()) == reference.name())
+            {
+                continue;
+            }
+            let oid = reference.peel_to_commit()?.id();
+            refs.entry(oid).or_default().push(reference);
+        }
+        for glob in &updates.globs {
+            for reference in repo.references_glob(glob)? {
+                let reference = reference?;
+                // annotated tags can't be moved without rewriting the tag object
+                let Some(oid) = reference.target() else {
+                    continue;
+                };
+                if reference.is_branch() || repo.find_commit(oid).is_err() {
+                    continue;
+                }
+                refs.entry(oid).or_default().push(reference);
+            }
         }
-        Ok(RepoBranches(branches))
+        Ok(RepoBranches(refs))
     }
 
-    /// Move branches whos commits have moved
+    /// Every ref that hasn't been retargeted yet, as (commit, full ref name)
+    pub(crate) fn pending(&self) -> Result<Vec<(Oid, String)>, anyhow::Error> {
+        let mut pending = vec![];
+        for (oid, refs) in &self.0 {
+            for reference in refs {
+                pending.push((*oid, ref_name(reference)?));
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Load the refs saved from `pending`
+    pub(crate) fn from_pending(
+        repo: &'a Repository,
+        pending: &[(Oid, String)],
+    ) -> Result<RepoBranches<'a>, anyhow::Error> {
+        let mut refs: HashMap<Oid, Vec<Reference>> = HashMap::new();
+        for (oid, name) in pending {
+            let reference = repo
+                .find_reference(name)
+                .with_context(|| format!("finding {name}"))?;
+            refs.entry(*oid).or_default().push(reference);
+        }
+        Ok(RepoBranches(refs))
+    }
+
+    /// The refs that would move to the commits in `rewrite`
+    ///
+    /// Unlike `retarget_branches` this includes HEAD's branch, since there is
+    /// no rebase to move it.
+    pub(crate) fn rewritten(
+        &self,
+        rewrite: &Rewrite,
+    ) -> Result<Vec<RetargetedBranch>, anyhow::Error> {
+        let mut retargeted = vec![];
+        for commit in &rewrite.commits {
+            for reference in self.0.get(&commit.original).into_iter().flatten() {
+                retargeted.push(RetargetedBranch {
+                    name: ref_name(reference)?,
+                    from: commit.original,
+                    to: commit.rewritten,
+                });
+            }
+        }
+        Ok(retargeted)
+    }
+
+    /// Move refs whos commits have moved
     pub(crate) fn retarget_branches(
         &mut self,
+        repo: &Repository,
         original_commit: Oid,
         target_commit: Oid,
         rebase: &mut Rebase<'_>,
     ) -> Result<Vec<RetargetedBranch>, anyhow::Error> {
         let mut retargeted = vec![];
-        if let Some(branches) = self.0.get_mut(&original_commit) {
-            // Don't retarget the last branch, rebase.finish does that for us
-            if rebase.operation_current() != Some(rebase.len() - 1) {
-                for branch in branches.iter_mut() {
-                    retargeted.push(RetargetedBranch {
-                        name: branch
-                            .name()
-                            .context("getting a branch name")?
-                            .ok_or(anyhow!("branch should have a name"))?
-                            .to_owned(),
-                        from: original_commit,
-                        to: target_commit,
-                    });
-                    branch
-                        .get_mut()
-                        .set_target(target_commit, "git-instafix retarget historical branch")?;
+        let rebased_branch = rebase.orig_head_name().map(str::to_owned);
+        if let Some(refs) = self.0.get_mut(&original_commit) {
+            for reference in refs.iter_mut() {
+                let name = ref_name(reference)?;
+                // Don't retarget the branch being rebased, rebase.finish does that for us
+                if Some(&name) == rebased_branch.as_ref() {
+                    continue;
                 }
+                reference.set_target(target_commit, "git-instafix retarget historical branch")?;
+                worktrees::sync(repo, &name, original_commit, target_commit)?;
+                retargeted.push(RetargetedBranch {
+                    name,
+                    from: original_commit,
+                    to: target_commit,
+                });
             }
         }
         Ok(retargeted)
     }
 }
 
-pub(crate) fn commit_parent<'a>(commit: &'a Commit) -> Result<Commit<'a>, anyhow::Error> {
-    match commit.parents().next() {
-        Some(c) => Ok(c),
-        None => bail!("Commit '{}' has no parents", commit_display(commit)),
+fn ref_name(reference: &Reference) -> Result<String, anyhow::Error> {
+    Ok(reference
+        .name()
+        .ok_or(anyhow!("ref name should be valid utf-8"))?
+        .to_owned())
+}
+
+/// Find the fixup target that all of the other targets descend from
+fn oldest_target<'a, 'r>(
+    repo: &Repository,
+    fixups: &'a [Fixup<'r>],
+) -> Result<&'a Commit<'r>, anyhow::Error> {
+    let mut oldest: Option<&Commit> = None;
+    for fixup in fixups {
+        oldest = match oldest {
+            Some(o) if repo.graph_descendant_of(fixup.target.id(), o.id())? => Some(o),
+            _ => Some(&fixup.target),
+        };
     }
+    oldest.ok_or_else(|| anyhow!("No commits to fix up"))
 }
@@ -0,0 +1,138 @@
+//! mod resume saves an instafix run that stopped at a conflict, so that it can be continued or aborted
+
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context as _;
+use git2::Oid;
+use git2::Repository;
+
+use crate::oplog::RefSnapshot;
+use crate::patcher;
+use crate::rebaser::Fixup;
+use crate::rebaser::Identities;
+
+/// Everything needed to finish a run after the rebase has stopped
+pub(crate) struct SavedRun {
+    /// (fixup target, fixup commit) for each fixup, in the order they were committed
+    pub(crate) fixups: Vec<(Oid, Oid)>,
+    /// The branches that still need to be retargeted, see `RepoBranches::pending`
+    pub(crate) branches: Vec<(Oid, String)>,
+    /// The stash of the working tree made before the rebase, if it was dirty
+    pub(crate) stash: Option<Oid>,
+    /// Where refs pointed before the run, to record for undo or restore on abort
+    pub(crate) snapshot: RefSnapshot,
+    /// Which author and committer the rebased commits get
+    pub(crate) identities: Identities,
+    /// Whether to skip the post-rewrite hook when the run finishes
+    pub(crate) no_verify: bool,
+    /// Whether the commit stopped at already has its fixup applied, see `Conflict`
+    pub(crate) fixup_applied: bool,
+}
+
+impl SavedRun {
+    /// Whether a stopped run has been saved in `repo`
+    pub(crate) fn exists(repo: &Repository) -> bool {
+        state_path(repo).exists()
+    }
+
+    pub(crate) fn save(&self, repo: &Repository) -> Result<(), anyhow::Error> {
+        let mut state = format!("no-verify {}\n", self.no_verify);
+        state.push_str(&format!("fixup-applied {}\n", self.fixup_applied));
+        if let Some(stash) = self.stash {
+            state.push_str(&format!("stash {stash}\n"));
+        }
+        let Identities {
+            keep_committer,
+            committer_date_is_author_date,
+            reset_author,
+        } = self.identities;
+        state.push_str(&format!(
+            "identities {keep_committer} {committer_date_is_author_date} {reset_author}\n"
+        ));
+        for (target, fixup_commit) in &self.fixups {
+            state.push_str(&format!("fixup {target} {fixup_commit}\n"));
+        }
+        for (oid, name) in &self.branches {
+            state.push_str(&format!("branch {oid} {name}\n"));
+        }
+        for (name, oid) in &self.snapshot.0 {
+            state.push_str(&format!("before {name} {oid}\n"));
+        }
+        fs::write(state_path(repo), state).context("saving instafix state")?;
+        Ok(())
+    }
+
+    pub(crate) fn load(repo: &Repository) -> Result<SavedRun, anyhow::Error> {
+        if !SavedRun::exists(repo) {
+            bail!("No stopped instafix run to continue or abort");
+        }
+        let state = fs::read_to_string(state_path(repo)).context("reading instafix state")?;
+        let mut run = SavedRun {
+            fixups: Vec::new(),
+            branches: Vec::new(),
+            stash: None,
+            snapshot: RefSnapshot(Default::default()),
+            identities: Identities::default(),
+            no_verify: false,
+            fixup_applied: false,
+        };
+        for line in state.lines() {
+            let parts = line.split(' ').collect::<Vec<_>>();
+            match parts[..] {
+                ["stash", stash] => run.stash = Some(Oid::from_str(stash)?),
+                ["no-verify", no_verify] => run.no_verify = no_verify == "true",
+                ["fixup-applied", applied] => run.fixup_applied = applied == "true",
+                ["identities", keep_committer, committer_date_is_author_date, reset_author] => {
+                    run.identities = Identities {
+                        keep_committer: keep_committer == "true",
+                        committer_date_is_author_date: committer_date_is_author_date == "true",
+                        reset_author: reset_author == "true",
+                    }
+                }
+                ["fixup", target, fixup_commit] => run
+                    .fixups
+                    .push((Oid::from_str(target)?, Oid::from_str(fixup_commit)?)),
+                ["branch", oid, name] => run.branches.push((Oid::from_str(oid)?, name.to_owned())),
+                ["before", name, oid] => {
+                    run.snapshot.0.insert(name.to_owned(), Oid::from_str(oid)?);
+                }
+                _ => bail!("unexpected line in instafix state: {line:?}"),
+            }
+        }
+        Ok(run)
+    }
+
+    pub(crate) fn clear(repo: &Repository) -> Result<(), anyhow::Error> {
+        fs::remove_file(state_path(repo)).context("removing instafix state")?;
+        Ok(())
+    }
+
+    /// Rebuild the fixups from the fixup commits, which are still in the rebase
+    pub(crate) fn load_fixups<'a>(
+        &self,
+        repo: &'a Repository,
+    ) -> Result<Vec<Fixup<'a>>, anyhow::Error> {
+        self.fixups
+            .iter()
+            .map(|(target, fixup_commit)| {
+                let fixup_commit = repo.find_commit(*fixup_commit)?;
+                Ok(Fixup {
+                    target: repo.find_commit(*target)?,
+                    diff: patcher::commit_diff(repo, &fixup_commit)?,
+                    base: fixup_commit.parent(0)?.tree()?,
+                    message: patcher::squash_message(&fixup_commit),
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn fixup_commits(&self) -> Vec<Oid> {
+        self.fixups.iter().map(|(_, c)| *c).collect()
+    }
+}
+
+fn state_path(repo: &Repository) -> PathBuf {
+    repo.path().join("instafix-state")
+}
@@ -1,128 +1,554 @@
 //! mod selector is responsible for tooling around selecting which commit to ammend
 
+mod fuzzy;
+
 use std::collections::HashMap;
+use std::iter::Peekable;
 
+use anyhow::Context as _;
 use anyhow::{anyhow, bail};
 use console::style;
+use console::Style;
+use console::Term;
 use dialoguer::Select;
-use git2::{Branch, BranchType, Commit, Oid, Reference, Repository};
+use git2::{Branch, BranchType, Commit, Diff, Oid, Reference, Repository};
+use regex::Regex;
+
+use fuzzy::FuzzySelect;
+use fuzzy::Item;
+use fuzzy::Selection;
 
+use crate::blamer;
+use crate::commit_display;
 use crate::config;
+use crate::config::PatternScope;
 use crate::format_ref;
+use crate::patcher;
+use crate::patcher::DiffHunks;
+use crate::patcher::HunkId;
+use crate::rebaser::Fixup;
+use crate::InteractionRequired;
 
 pub(crate) struct CommitSelection<'a> {
     pub commit: Commit<'a>,
     pub reference: Reference<'a>,
 }
 
-pub(crate) fn select_commit_to_amend<'a>(
+/// The commits between HEAD and `upstream` that are candidates for amending, newest first
+pub(crate) fn commits_in_range<'a>(
     repo: &'a Repository,
-    upstream: Option<CommitSelection>,
+    upstream: Option<&CommitSelection>,
     max_commits: usize,
-    message_pattern: Option<&str>,
-) -> Result<Commit<'a>, anyhow::Error> {
-    let mut walker = repo.revwalk()?;
-    walker.push_head()?;
-    let commits = if let Some(upstream) = upstream.as_ref() {
-        let upstream_oid = upstream.commit.id();
-        let commits = walker
-            .flatten()
-            .take_while(|rev| *rev != upstream_oid)
-            .take(max_commits)
-            .map(|rev| repo.find_commit(rev))
-            .collect::<Result<Vec<_>, _>>()?;
+) -> Result<Vec<Commit<'a>>, anyhow::Error> {
+    RangeWalk::new(repo, upstream)?.first_page(max_commits)
+}
 
-        let head = repo.head()?;
-        let current_branch_name = head
-            .shorthand()
-            .ok_or_else(|| anyhow!("HEAD's name is invalid utf-8"))?;
-        if repo.head()?.peel_to_commit()?.id() == upstream.commit.id()
-            && current_branch_name == upstream.reference.name().unwrap()
-        {
-            let upstream_setting = config::UPSTREAM_SETTING;
+/// Walks the commits between HEAD and `upstream` a page at a time, newest first
+pub(crate) struct RangeWalk<'a> {
+    repo: &'a Repository,
+    revs: Peekable<Box<dyn Iterator<Item = Oid> + 'a>>,
+    upstream: Option<Oid>,
+    /// The number of commits in the whole range, if it ends at an upstream commit
+    total: Option<usize>,
+    taken: usize,
+}
+
+impl<'a> RangeWalk<'a> {
+    pub(crate) fn new(
+        repo: &'a Repository,
+        upstream: Option<&CommitSelection>,
+    ) -> Result<RangeWalk<'a>, anyhow::Error> {
+        if let Some(upstream) = upstream {
+            let head = repo.head()?;
+            let current_branch_name = head
+                .shorthand()
+                .ok_or_else(|| anyhow!("HEAD's name is invalid utf-8"))?;
+            if repo.head()?.peel_to_commit()?.id() == upstream.commit.id()
+                && current_branch_name == upstream.reference.name().unwrap()
+            {
+                let upstream_setting = config::UPSTREAM_SETTING;
+                bail!(
+                    "HEAD is already pointing at a common upstream branch\n\
+                If you don't create branches for your work consider setting upstream to a remote ref:\n\
+                \n    \
+                    git config {upstream_setting} origin/{current_branch_name}"
+                )
+            }
+        }
+        let upstream = upstream.map(|u| u.commit.id());
+        let total = match upstream {
+            Some(_) => Some(Self::revs(repo, upstream)?.count()),
+            None => None,
+        };
+        Ok(RangeWalk {
+            repo,
+            revs: Self::revs(repo, upstream)?.peekable(),
+            upstream,
+            total,
+            taken: 0,
+        })
+    }
+
+    fn revs(
+        repo: &'a Repository,
+        upstream: Option<Oid>,
+    ) -> Result<Box<dyn Iterator<Item = Oid> + 'a>, anyhow::Error> {
+        let mut walker = repo.revwalk()?;
+        walker.push_head()?;
+        Ok(Box::new(
+            walker
+                .flatten()
+                .take_while(move |rev| Some(*rev) != upstream),
+        ))
+    }
+
+    /// The first `n` commits, failing if there aren't any
+    pub(crate) fn first_page(&mut self, n: usize) -> Result<Vec<Commit<'a>>, anyhow::Error> {
+        let commits = self.next_page(n)?;
+        if commits.is_empty() {
             bail!(
-                "HEAD is already pointing at a common upstream branch\n\
-            If you don't create branches for your work consider setting upstream to a remote ref:\n\
-            \n    \
-                git config {upstream_setting} origin/{current_branch_name}"
-            )
+                "No commits between {} and {:?}",
+                format_ref(&self.repo.head()?)?,
+                self.upstream
+                    .map(|u| u.to_string())
+                    .unwrap_or_else(|| "<no upstream>".to_string())
+            );
         }
-        commits
-    } else {
-        walker
-            .flatten()
-            .take(max_commits)
-            .map(|rev| repo.find_commit(rev))
-            .collect::<Result<Vec<_>, _>>()?
-    };
-    if commits.is_empty() {
-        bail!(
-            "No commits between {} and {:?}",
-            format_ref(&repo.head()?)?,
-            upstream
-                .map(|u| u.commit.id().to_string())
-                .unwrap_or_else(|| "<no upstream>".to_string())
-        );
+        Ok(commits)
     }
-    let branches: HashMap<Oid, String> = repo
-        .branches(None)?
-        .filter_map(|b| {
-            b.ok().and_then(|(b, _type)| {
-                let name: Option<String> = b.name().ok().and_then(|n| n.map(|n| n.to_owned()));
-                let oid = b.into_reference().resolve().ok().and_then(|r| r.target());
-                name.and_then(|name| oid.map(|oid| (oid, name)))
-            })
-        })
-        .collect();
-    if let Some(message_pattern) = message_pattern.as_ref() {
+
+    pub(crate) fn next_page(&mut self, n: usize) -> Result<Vec<Commit<'a>>, anyhow::Error> {
+        let commits = self
+            .revs
+            .by_ref()
+            .take(n)
+            .map(|rev| self.repo.find_commit(rev))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.taken += commits.len();
+        Ok(commits)
+    }
+
+    pub(crate) fn has_more(&mut self) -> bool {
+        self.revs.peek().is_some()
+    }
+
+    /// How many commits are left before the upstream commit, if there is one
+    pub(crate) fn remaining(&self) -> Option<usize> {
+        self.total.map(|total| total - self.taken)
+    }
+}
+
+pub(crate) fn select_commit_to_amend<'a>(
+    repo: &'a Repository,
+    upstream: Option<&CommitSelection>,
+    max_commits: usize,
+    message_pattern: Option<&MessagePattern>,
+    diff: &Diff<'_>,
+    theme: &str,
+    non_interactive: bool,
+) -> Result<Commit<'a>, anyhow::Error> {
+    let mut walk = RangeWalk::new(repo, upstream)?;
+    let commits = walk.first_page(max_commits)?;
+    let picker = CommitPicker {
+        repo,
+        upstream,
+        max_commits,
+        diff,
+        theme,
+    };
+    if let Some(message_pattern) = message_pattern {
+        let mut commits = commits;
+        // the whole range up to the merge-base is searched, but without an
+        // upstream that could be all of history, so stick to the first page
+        if walk.remaining().is_some() {
+            commits.extend(walk.next_page(usize::MAX)?);
+        }
         let first = commit_id_and_summary(&commits, commits.len() - 1);
         let last = commit_id_and_summary(&commits, 0);
-        commits
+        let not_searched = if walk.has_more() {
+            format!(
+                "\nOlder commits were not searched since HEAD has no upstream, \
+                use --max-commits to search more than {max_commits}"
+            )
+        } else {
+            String::new()
+        };
+        let mut matches = commits
             .into_iter()
-            .find(|commit| {
-                commit
-                    .summary()
-                    .map(|s| s.contains(message_pattern))
-                    .unwrap_or(false)
-            })
-            .ok_or_else(|| {
-                anyhow::anyhow!(
-                    "No commit contains the pattern in its summary between {}..{}",
-                    first,
-                    last
-                )
-            })
+            .filter(|commit| message_pattern.matches(commit))
+            .collect::<Vec<_>>();
+        match matches.len() {
+            0 => bail!(
+                "No commit contains the pattern in its {} between {}..{}{}",
+                message_pattern.scope,
+                first,
+                last,
+                not_searched
+            ),
+            1 => Ok(matches.remove(0)),
+            _ if !non_interactive && Term::stderr().is_term() => picker.pick(matches, None),
+            _ => {
+                let listed = matches
+                    .iter()
+                    .map(|c| format!("\n    {}", commit_display(c)))
+                    .collect::<String>();
+                Err(InteractionRequired(format!(
+                    "Multiple commits match the pattern in their {}:{listed}\n\
+                    Use a more specific pattern or choose a commit with --commit",
+                    message_pattern.scope
+                ))
+                .into())
+            }
+        }
+    } else if non_interactive {
+        Err(InteractionRequired(
+            "Not asking which commit to amend when running non-interactively\n\
+            Choose one with --commit, --commit-message-pattern or --absorb"
+                .into(),
+        )
+        .into())
     } else {
-        let rev_aliases = commits
+        picker.pick(commits, Some(walk))
+    }
+}
+
+/// Interactive selection of a commit to amend
+struct CommitPicker<'p, 'a> {
+    repo: &'a Repository,
+    upstream: Option<&'p CommitSelection<'p>>,
+    max_commits: usize,
+    diff: &'p Diff<'p>,
+    theme: &'p str,
+}
+
+impl<'p, 'a> CommitPicker<'p, 'a> {
+    /// Choose one of `commits`, loading more from `walk` on request if it's provided
+    fn pick(
+        &self,
+        mut commits: Vec<Commit<'a>>,
+        mut walk: Option<RangeWalk<'a>>,
+    ) -> Result<Commit<'a>, anyhow::Error> {
+        let repo = self.repo;
+        let branches: HashMap<Oid, String> = repo
+            .branches(None)?
+            .filter_map(|b| {
+                b.ok().and_then(|(b, _type)| {
+                    let name: Option<String> = b.name().ok().and_then(|n| n.map(|n| n.to_owned()));
+                    let oid = b.into_reference().resolve().ok().and_then(|r| r.target());
+                    name.and_then(|name| oid.map(|oid| (oid, name)))
+                })
+            })
+            .collect();
+        let hunk_blames =
+            blamer::blame_hunks(repo, self.diff, self.upstream.map(|u| u.commit.id()))
+                .context("blaming staged changes")?;
+        let suggestions = blamer::Suggestions::from_blames(&hunk_blames);
+        let mut items = commits
             .iter()
             .enumerate()
-            .map(|(i, commit)| {
-                let bname = if i > 0 {
-                    branches
-                        .get(&commit.id())
-                        .map(|n| format!("({}) ", n))
-                        .unwrap_or_default()
-                } else {
-                    String::new()
-                };
-                format!(
-                    "{} {}{}",
-                    &style(&commit.id().to_string()[0..10]).blue(),
-                    style(bname).green(),
-                    commit.summary().unwrap_or("no commit summary")
-                )
-            })
+            .map(|(i, commit)| commit_item(i, commit, &branches, &suggestions))
             .collect::<Vec<_>>();
-        if upstream.is_none() {
-            println!("Select a commit to amend (no upstream for HEAD):");
+        let prompt = if self.upstream.is_none() {
+            "Select a commit to amend (no upstream for HEAD):"
+        } else {
+            "Select a commit to amend:"
+        };
+        let mut default = suggestions
+            .best_match(commits.iter().map(|c| c.id()))
+            .unwrap_or(0);
+        let mut query = String::new();
+        let mut previews: HashMap<usize, Vec<String>> = HashMap::new();
+        loop {
+            let more = walk
+                .as_mut()
+                .map(|walk| walk.has_more())
+                .unwrap_or(false)
+                .then(|| format!("load {} more…", self.max_commits));
+            let footer = match walk.as_ref().and_then(|w| w.remaining()) {
+                Some(0) => None,
+                Some(1) => Some("1 more commit before the upstream merge-base".to_string()),
+                Some(n) => Some(format!("{n} more commits before the upstream merge-base")),
+                None => more.as_ref().map(|_| "more commits in history".to_string()),
+            };
+            let selection = FuzzySelect::new(prompt, &items)
+                .default(default)
+                .query(query)
+                .more(more)
+                .footer(footer)
+                .preview(|i, height| {
+                    let lines = previews.entry(i).or_insert_with(|| {
+                        let width = Term::stderr().size().1 as usize;
+                        patcher::commit_preview(repo, &commits[i], self.theme, width)
+                            .unwrap_or_else(|e| vec![format!("Unable to show commit: {e}")])
+                    });
+                    preview_lines(lines, height)
+                })
+                .interact()?;
+            match (selection, walk.as_mut()) {
+                (Selection::Item(i), _) => return Ok(repo.find_commit(commits[i].id())?),
+                (Selection::More(typed), Some(walk)) => {
+                    query = typed;
+                    default = commits.len();
+                    commits.extend(walk.next_page(self.max_commits)?);
+                    items.extend(
+                        commits
+                            .iter()
+                            .enumerate()
+                            .skip(default)
+                            .map(|(i, commit)| commit_item(i, commit, &branches, &suggestions)),
+                    );
+                }
+                (Selection::More(_), None) => unreachable!("more is only offered with a walk"),
+            }
+        }
+    }
+}
+
+/// Which commits a `--commit-message-pattern` selects
+pub(crate) struct MessagePattern {
+    matcher: Matcher,
+    scope: PatternScope,
+}
+
+enum Matcher {
+    Substring(String),
+    Regex(Regex),
+}
+
+impl MessagePattern {
+    pub(crate) fn new(
+        pattern: &str,
+        regex: bool,
+        scope: PatternScope,
+    ) -> Result<MessagePattern, anyhow::Error> {
+        let matcher = if regex {
+            Matcher::Regex(Regex::new(pattern).context("parsing commit message pattern")?)
         } else {
-            println!("Select a commit to amend:");
+            Matcher::Substring(pattern.to_owned())
+        };
+        Ok(MessagePattern { matcher, scope })
+    }
+
+    fn matches(&self, commit: &Commit<'_>) -> bool {
+        let is_match = |text: &str| match &self.matcher {
+            Matcher::Substring(pattern) => text.contains(pattern.as_str()),
+            Matcher::Regex(regex) => regex.is_match(text),
+        };
+        match &self.scope {
+            PatternScope::Summary => commit.summary().map(is_match).unwrap_or(false),
+            PatternScope::Message => commit.message().map(is_match).unwrap_or(false),
+            PatternScope::Author => {
+                let author = commit.author();
+                is_match(&format!(
+                    "{} <{}>",
+                    author.name().unwrap_or_default(),
+                    author.email().unwrap_or_default()
+                ))
+            }
+            PatternScope::Trailer(key) => commit
+                .message()
+                .and_then(|message| git2::message_trailers_strs(message).ok())
+                .map(|trailers| {
+                    trailers.iter().any(|(k, v)| {
+                        key.as_ref()
+                            .map(|key| key.eq_ignore_ascii_case(k))
+                            .unwrap_or(true)
+                            && is_match(v)
+                    })
+                })
+                .unwrap_or(false),
         }
-        let selected = Select::new().items(&rev_aliases).default(0).interact();
-        Ok(repo.find_commit(commits[selected?].id())?)
     }
 }
 
+/// Find the commit to amend from a revision like `HEAD~3` or `:/message`
+///
+/// The commit must be HEAD or one of its ancestors, and must come after `upstream`.
+pub(crate) fn find_commit_by_rev<'a>(
+    repo: &'a Repository,
+    upstream: Option<&CommitSelection>,
+    rev: &str,
+) -> Result<Commit<'a>, anyhow::Error> {
+    let commit = repo
+        .revparse_single(rev)
+        .and_then(|obj| obj.peel_to_commit())
+        .with_context(|| format!("finding commit {rev:?}"))?;
+    let head = repo.head()?.peel_to_commit()?;
+    if commit.id() != head.id() && !repo.graph_descendant_of(head.id(), commit.id())? {
+        bail!(
+            "{} is not an ancestor of HEAD ({})",
+            commit_display(&commit),
+            commit_display(&head)
+        );
+    }
+    if let Some(upstream) = upstream {
+        let upstream_id = upstream.commit.id();
+        if commit.id() == upstream_id || !repo.graph_descendant_of(commit.id(), upstream_id)? {
+            bail!(
+                "{} is not after the upstream merge-base {}\n\
+                Use --default-upstream-branch to choose a different upstream",
+                commit_display(&commit),
+                commit_display(&upstream.commit)
+            );
+        }
+    }
+    Ok(commit)
+}
+
+/// Display a commit in the selector
+fn commit_item(
+    i: usize,
+    commit: &Commit<'_>,
+    branches: &HashMap<Oid, String>,
+    suggestions: &blamer::Suggestions,
+) -> Item {
+    let bname = if i > 0 {
+        branches
+            .get(&commit.id())
+            .map(|n| format!("({})", n))
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+    let annotation = suggestions
+        .annotation(commit.id())
+        .map(|a| style(a).for_stderr().yellow().to_string())
+        .unwrap_or_default();
+    Item::new(commit.id().to_string())
+        .field(&commit.id().to_string()[0..10], Style::new().blue())
+        .field(bname, Style::new().green())
+        .field(
+            commit.summary().unwrap_or("no commit summary"),
+            Style::new(),
+        )
+        .field(
+            format!("<{}>", commit.author().name().unwrap_or("unknown")),
+            Style::new().dim(),
+        )
+        .suffix(annotation)
+}
+
+/// Fit a commit preview into `height` lines
+///
+/// The diffstat always comes first, the patch is only shown if there's room
+/// for some of it after the diffstat.
+fn preview_lines(lines: &[String], height: usize) -> Vec<String> {
+    let stat_len = lines
+        .iter()
+        .position(|l| l.is_empty())
+        .unwrap_or(lines.len());
+    let shown = if stat_len + 3 >= height {
+        &lines[..stat_len.min(height)]
+    } else {
+        &lines[..lines.len().min(height)]
+    };
+    let mut shown = shown.to_vec();
+    if shown.len() < lines.len() && !shown.is_empty() {
+        shown.pop();
+        shown.push(style("…").for_stderr().dim().to_string());
+    }
+    shown
+}
+
+/// Interactively assign each staged file or hunk to a commit to amend
+///
+/// Returns one fixup per commit that had changes assigned to it, oldest first.
+/// Anything that isn't assigned is left out of the fixups and so stays staged.
+pub(crate) fn split_between_commits<'a>(
+    repo: &'a Repository,
+    upstream: Option<&CommitSelection>,
+    max_commits: usize,
+    diff: &Diff<'_>,
+    theme: &str,
+    non_interactive: bool,
+) -> Result<Vec<Fixup<'a>>, anyhow::Error> {
+    if non_interactive {
+        return Err(InteractionRequired("--split needs a terminal to ask on".into()).into());
+    }
+    let commits = commits_in_range(repo, upstream, max_commits)?;
+    let hunks = DiffHunks::from_diff(diff)?;
+    let hunk_blames = blamer::blame_hunks(repo, diff, upstream.map(|u| u.commit.id()))
+        .context("blaming staged changes")?;
+    let commit_items = commits.iter().map(commit_display).collect::<Vec<_>>();
+    let suggest = |f: usize, h: Option<usize>| {
+        blamer::Suggestions::from_blames(
+            hunk_blames
+                .iter()
+                .filter(|b| b.id.0 == f && h.map(|h| b.id.1 == h).unwrap_or(true)),
+        )
+        .best_match(commits.iter().map(|c| c.id()))
+        .unwrap_or(0)
+    };
+
+    let mut assigned: HashMap<HunkId, usize> = HashMap::new();
+    for (f, file) in hunks.files.iter().enumerate() {
+        let file_hunks = hunks.file_hunk_ids(f).collect::<Vec<_>>();
+        let mut items = commit_items.clone();
+        let split_choice = if file_hunks.len() > 1 {
+            items.push(style("Split this file up by hunk").cyan().to_string());
+            Some(items.len() - 1)
+        } else {
+            None
+        };
+        items.push(style("Leave staged").cyan().to_string());
+        let leave_choice = items.len() - 1;
+
+        let selected = Select::new()
+            .with_prompt(format!("Commit to amend with {}", file.path.display()))
+            .items(&items)
+            .default(suggest(f, None))
+            .interact()?;
+        if Some(selected) == split_choice {
+            for id in file_hunks {
+                if let Some(hunk_diff) = hunks.select(|h| h == id)? {
+                    patcher::print_diff(&hunk_diff, theme)?;
+                }
+                let mut items = commit_items.clone();
+                items.push(style("Leave staged").cyan().to_string());
+                let selected = Select::new()
+                    .with_prompt(format!("Commit to amend with {}", hunks.describe(id)))
+                    .items(&items)
+                    .default(suggest(f, Some(id.1)))
+                    .interact()?;
+                if selected < commits.len() {
+                    assigned.insert(id, selected);
+                }
+            }
+        } else if selected != leave_choice {
+            assigned.extend(file_hunks.into_iter().map(|id| (id, selected)));
+        }
+    }
+
+    assigned_fixups(repo, &commits, &hunks, &assigned)
+}
+
+/// One fixup for each of `commits` that hunks were assigned to, oldest first
+///
+/// `assigned` maps hunks to the index of their commit in `commits`.
+fn assigned_fixups<'a>(
+    repo: &'a Repository,
+    commits: &[Commit<'a>],
+    hunks: &DiffHunks,
+    assigned: &HashMap<HunkId, usize>,
+) -> Result<Vec<Fixup<'a>>, anyhow::Error> {
+    let head_tree = repo.head()?.peel_to_tree()?;
+    let mut fixups = Vec::new();
+    for (i, target) in commits.iter().enumerate().rev() {
+        if let Some(diff) = hunks.select(|id| assigned.get(&id) == Some(&i))? {
+            fixups.push(Fixup {
+                target: target.clone(),
+                diff,
+                base: head_tree.clone(),
+                message: None,
+            });
+        }
+    }
+    if fixups.is_empty() {
+        bail!("No staged changes were assigned to a commit");
+    }
+    Ok(fixups)
+}
+
 pub(crate) fn get_merge_base<'a>(
     repo: &'a Repository,
     head_branch: &'a Branch,
@@ -146,13 +572,18 @@ pub(crate) fn get_merge_base<'a>(
         return Ok(None);
     };
 
-    let mb = repo.merge_base(
+    let mb = match repo.merge_base(
         head_branch
             .get()
             .target()
             .expect("all branches should have a target"),
         upstream.id(),
-    )?;
+    ) {
+        Ok(mb) => mb,
+        // an orphan branch shares no history with upstream, so all of it is ours
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
     let commit = repo.find_object(mb, None).unwrap();
 
     Ok(Some(CommitSelection {
@@ -176,8 +607,161 @@ pub(crate) fn commit_id_and_summary(commits: &[Commit<'_>], idx: usize) -> Strin
 }
 
 /// Check if any of the `config::DEFAULT_UPSTREAM_BRANCHES` exist in the repository
-fn find_default_upstream_branch(repo: &Repository) -> Option<Branch> {
+fn find_default_upstream_branch(repo: &Repository) -> Option<Branch<'_>> {
     crate::config::DEFAULT_UPSTREAM_BRANCHES
         .iter()
         .find_map(|b| repo.find_branch(b, BranchType::Local).ok())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use crate::patcher::tests::commit_file;
+
+    /// A repo with a `base` commit on the `upstream` branch and `n` commits after it on HEAD
+    fn repo_with_commits(n: usize) -> (assert_fs::TempDir, Repository) {
+        let td = assert_fs::TempDir::new().unwrap();
+        let repo = Repository::init(td.path()).unwrap();
+        let base = commit_file(&repo, "base", b"base\n");
+        repo.branch("upstream", &base, false).unwrap();
+        drop(base);
+        for i in 1..=n {
+            commit_file(&repo, &format!("c{i}"), b"c\n");
+        }
+        (td, repo)
+    }
+
+    fn summaries(commits: &[Commit]) -> Vec<String> {
+        commits
+            .iter()
+            .map(|c| c.summary().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn range_walk_pages_up_to_the_merge_base() {
+        let (_td, repo) = repo_with_commits(5);
+        let upstream = CommitSelection {
+            commit: repo
+                .revparse_single("upstream")
+                .unwrap()
+                .peel_to_commit()
+                .unwrap(),
+            reference: repo.find_reference("refs/heads/upstream").unwrap(),
+        };
+        let mut walk = RangeWalk::new(&repo, Some(&upstream)).unwrap();
+        assert_eq!(walk.remaining(), Some(5));
+        assert_eq!(summaries(&walk.first_page(2).unwrap()), ["c5", "c4"]);
+        assert_eq!(walk.remaining(), Some(3));
+        assert!(walk.has_more());
+        assert_eq!(summaries(&walk.next_page(2).unwrap()), ["c3", "c2"]);
+        assert_eq!(walk.remaining(), Some(1));
+        assert_eq!(summaries(&walk.next_page(2).unwrap()), ["c1"]);
+        assert_eq!(walk.remaining(), Some(0));
+        assert!(!walk.has_more());
+        assert!(walk.next_page(2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_assignments_become_one_fixup_per_commit() {
+        let (td, repo) = repo_with_commits(3);
+        for (name, content) in [("c1", "one\n"), ("c2", "two\n"), ("c3", "three\n")] {
+            std::fs::write(td.path().join(name), content).unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["c1", "c2", "c3"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.write().unwrap();
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let diff = repo
+            .diff_tree_to_index(Some(&head_tree), None, None)
+            .unwrap();
+        let hunks = DiffHunks::from_diff(&diff).unwrap();
+        let commits = RangeWalk::new(&repo, None).unwrap().first_page(3).unwrap();
+        assert_eq!(summaries(&commits), ["c3", "c2", "c1"]);
+
+        // c1's change goes to c1, c3's to c1 as well, and c2's is left staged
+        let assigned = HashMap::from([((0, 0), 2), ((2, 0), 2)]);
+        let fixups = assigned_fixups(&repo, &commits, &hunks, &assigned).unwrap();
+        assert_eq!(fixups.len(), 1);
+        assert_eq!(fixups[0].target.summary(), Some("c1"));
+        let paths = fixups[0]
+            .diff
+            .deltas()
+            .map(|d| d.new_file().path().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [Path::new("c1"), Path::new("c3")]);
+
+        // fixups come oldest first
+        let assigned = HashMap::from([((0, 0), 2), ((1, 0), 0)]);
+        let fixups = assigned_fixups(&repo, &commits, &hunks, &assigned).unwrap();
+        let targets = fixups
+            .iter()
+            .map(|f| f.target.summary().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(targets, ["c1", "c3"]);
+
+        let err = assigned_fixups(&repo, &commits, &hunks, &HashMap::new()).err();
+        assert!(err.is_some());
+    }
+
+    #[test]
+    fn range_walk_without_upstream_pages_through_history() {
+        let (_td, repo) = repo_with_commits(2);
+        let mut walk = RangeWalk::new(&repo, None).unwrap();
+        assert_eq!(walk.remaining(), None);
+        assert_eq!(summaries(&walk.first_page(2).unwrap()), ["c2", "c1"]);
+        assert!(walk.has_more());
+        assert_eq!(summaries(&walk.next_page(2).unwrap()), ["base"]);
+        assert!(!walk.has_more());
+    }
+
+    fn preview(height: usize) -> Vec<String> {
+        let lines = [
+            " file_a | 2 +-",
+            " 1 file changed",
+            "",
+            "diff --git a/file_a b/file_a",
+            "-old",
+            "+new",
+            "+more",
+            "+and more",
+        ]
+        .map(String::from);
+        preview_lines(&lines, height)
+            .iter()
+            .map(|l| console::strip_ansi_codes(l).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn preview_fits_everything_when_there_is_room() {
+        assert_eq!(preview(8).len(), 8);
+        assert_eq!(preview(20).len(), 8);
+    }
+
+    #[test]
+    fn preview_cuts_off_the_patch() {
+        assert_eq!(
+            preview(6),
+            [
+                " file_a | 2 +-",
+                " 1 file changed",
+                "",
+                "diff --git a/file_a b/file_a",
+                "-old",
+                "…"
+            ]
+        );
+    }
+
+    #[test]
+    fn preview_only_shows_the_diffstat_without_room_for_the_patch() {
+        assert_eq!(preview(5), [" file_a | 2 +-", "…"]);
+        assert_eq!(preview(1), ["…"]);
+        assert!(preview(0).is_empty());
+    }
+}
@@ -0,0 +1,450 @@
+//! A type-to-filter list picker, for when the list is too long to scroll through
+
+use anyhow::bail;
+use console::truncate_str;
+use console::Key;
+use console::Style;
+use console::Term;
+
+/// An entry in a [`FuzzySelect`]
+pub(crate) struct Item {
+    /// Searchable text, each with the style it is displayed in
+    fields: Vec<(String, Style)>,
+    /// Displayed after the fields but not searched
+    suffix: String,
+    /// Typing a prefix of this moves the item to the top of the list
+    id: String,
+}
+
+impl Item {
+    pub(crate) fn new(id: String) -> Item {
+        Item {
+            fields: Vec::new(),
+            suffix: String::new(),
+            id,
+        }
+    }
+
+    /// Add searchable text to the item, empty fields are skipped
+    pub(crate) fn field(mut self, text: impl Into<String>, style: Style) -> Item {
+        let text = text.into();
+        if !text.is_empty() {
+            self.fields.push((text, style.for_stderr()));
+        }
+        self
+    }
+
+    pub(crate) fn suffix(mut self, suffix: String) -> Item {
+        self.suffix = suffix;
+        self
+    }
+
+    /// All of the searchable text, as it is displayed
+    fn haystack(&self) -> String {
+        self.fields
+            .iter()
+            .map(|(text, _)| text.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Render the item, highlighting the chars at `positions` in the haystack
+    fn render(&self, positions: &[usize]) -> String {
+        let mut out = String::new();
+        let mut idx = 0;
+        for (i, (text, style)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                out.push(' ');
+                idx += 1;
+            }
+            // style runs of chars at a time so that we don't emit escapes for every char
+            let mut run = String::new();
+            let mut run_highlighted = false;
+            for ch in text.chars() {
+                let highlighted = positions.contains(&idx);
+                if highlighted != run_highlighted && !run.is_empty() {
+                    out.push_str(&run_style(style, run_highlighted).apply_to(&run).to_string());
+                    run.clear();
+                }
+                run.push(ch);
+                run_highlighted = highlighted;
+                idx += 1;
+            }
+            out.push_str(&run_style(style, run_highlighted).apply_to(&run).to_string());
+        }
+        if !self.suffix.is_empty() {
+            out.push(' ');
+            out.push_str(&self.suffix);
+        }
+        out
+    }
+}
+
+fn run_style(style: &Style, highlighted: bool) -> Style {
+    if highlighted {
+        style.clone().bold().underlined()
+    } else {
+        style.clone()
+    }
+}
+
+/// A selection prompt that narrows its items as the user types
+pub(crate) struct FuzzySelect<'a> {
+    prompt: String,
+    items: &'a [Item],
+    default: usize,
+    query: String,
+    more: Option<String>,
+    footer: Option<String>,
+    preview: Option<Preview<'a>>,
+}
+
+/// What the user chose in a [`FuzzySelect`]
+pub(crate) enum Selection {
+    /// The index of the chosen item
+    Item(usize),
+    /// The user asked for more items, along with what they had typed so far
+    More(String),
+}
+
+/// Shows the terminal's cursor again however the prompt is left, even by a panic
+struct ShowCursorOnDrop<'t>(&'t Term);
+
+impl Drop for ShowCursorOnDrop<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.show_cursor();
+    }
+}
+
+/// Produces at most `height` lines describing the item at `index`
+type Preview<'a> = Box<dyn FnMut(usize, usize) -> Vec<String> + 'a>;
+
+struct Match {
+    item: usize,
+    score: i64,
+    positions: Vec<usize>,
+}
+
+impl<'a> FuzzySelect<'a> {
+    pub(crate) fn new(prompt: impl Into<String>, items: &'a [Item]) -> FuzzySelect<'a> {
+        FuzzySelect {
+            prompt: prompt.into(),
+            items,
+            default: 0,
+            query: String::new(),
+            more: None,
+            footer: None,
+            preview: None,
+        }
+    }
+
+    /// Start out with some text already typed
+    pub(crate) fn query(mut self, query: String) -> FuzzySelect<'a> {
+        self.query = query;
+        self
+    }
+
+    /// Add a row after the items that, when chosen, returns [`Selection::More`]
+    pub(crate) fn more(mut self, label: Option<String>) -> FuzzySelect<'a> {
+        self.more = label;
+        self
+    }
+
+    /// A line of extra information displayed below the list
+    pub(crate) fn footer(mut self, footer: Option<String>) -> FuzzySelect<'a> {
+        self.footer = footer;
+        self
+    }
+
+    /// The item that is highlighted before anything is typed
+    pub(crate) fn default(mut self, default: usize) -> FuzzySelect<'a> {
+        self.default = default;
+        self
+    }
+
+    /// Show details about the highlighted item below the list
+    ///
+    /// `preview` is called with the index of the highlighted item and the
+    /// number of lines available, and should return at most that many lines.
+    pub(crate) fn preview(
+        mut self,
+        preview: impl FnMut(usize, usize) -> Vec<String> + 'a,
+    ) -> FuzzySelect<'a> {
+        self.preview = Some(Box::new(preview));
+        self
+    }
+
+    /// Show the prompt and return what the user chose
+    pub(crate) fn interact(mut self) -> Result<Selection, anyhow::Error> {
+        let term = Term::stderr();
+        if !term.is_term() {
+            bail!("Unable to select a commit: stderr is not a terminal");
+        }
+        term.hide_cursor()?;
+        let _cursor = ShowCursorOnDrop(&term);
+        self.interact_on(&term)
+    }
+
+    fn interact_on(&mut self, term: &Term) -> Result<Selection, anyhow::Error> {
+        let mut query = std::mem::take(&mut self.query);
+        let mut cursor = if query.is_empty() { self.default } else { 0 };
+        let mut drawn = 0;
+        loop {
+            let matches = self.matches(&query);
+            let rows_len = matches.len() + usize::from(self.more.is_some());
+            cursor = cursor.min(rows_len.saturating_sub(1));
+
+            let (rows, cols) = term.size();
+            let available = (rows as usize).saturating_sub(2).max(4);
+            // leave most of the screen for the preview, if there is one
+            let height = if self.preview.is_some() {
+                (available / 3).max(3)
+            } else {
+                available - 1
+            };
+            let mut lines = self.render(&query, &matches, cursor, height);
+            if let (Some(preview), Some(m)) = (self.preview.as_mut(), matches.get(cursor)) {
+                let room = available.saturating_sub(lines.len() + 1);
+                if room > 0 {
+                    let rule = "─".repeat(cols as usize - 1);
+                    lines.push(Style::new().for_stderr().dim().apply_to(rule).to_string());
+                    lines.extend(preview(m.item, room).into_iter().take(room));
+                }
+            }
+            term.clear_last_lines(drawn)?;
+            for line in &lines {
+                term.write_line(&truncate_str(line, cols as usize - 1, "…"))?;
+            }
+            drawn = lines.len();
+
+            match term.read_key()? {
+                Key::Enter => {
+                    if let Some(m) = matches.get(cursor) {
+                        term.clear_last_lines(drawn)?;
+                        return Ok(Selection::Item(m.item));
+                    } else if cursor < rows_len {
+                        term.clear_last_lines(drawn)?;
+                        return Ok(Selection::More(query));
+                    }
+                }
+                Key::Escape | Key::CtrlC => {
+                    term.clear_last_lines(drawn)?;
+                    bail!("");
+                }
+                Key::ArrowUp => cursor = cursor.saturating_sub(1),
+                Key::ArrowDown => cursor += 1,
+                Key::PageUp => cursor = cursor.saturating_sub(height),
+                Key::PageDown => cursor += height,
+                Key::Home => cursor = 0,
+                Key::End => cursor = rows_len,
+                Key::Backspace => {
+                    query.pop();
+                    cursor = 0;
+                }
+                Key::Char(c) if !c.is_control() => {
+                    query.push(c);
+                    cursor = 0;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn render(&self, query: &str, matches: &[Match], cursor: usize, height: usize) -> Vec<String> {
+        let dim = Style::new().for_stderr().dim();
+        let mut lines = vec![format!(
+            "{} {}{}",
+            Style::new().for_stderr().bold().apply_to(&self.prompt),
+            Style::new().for_stderr().cyan().apply_to("> "),
+            query
+        )];
+        let mut rows = matches
+            .iter()
+            .map(|m| self.items[m.item].render(&m.positions))
+            .collect::<Vec<_>>();
+        if rows.is_empty() {
+            lines.push(dim.apply_to("  no matches").to_string());
+        }
+        if let Some(more) = &self.more {
+            rows.push(Style::new().for_stderr().cyan().apply_to(more).to_string());
+        }
+        let start = (cursor + 1).saturating_sub(height);
+        for (i, row) in rows.iter().enumerate().skip(start).take(height) {
+            let marker = if i == cursor { "> " } else { "  " };
+            lines.push(format!("{marker}{row}"));
+        }
+        if rows.len() > height {
+            lines.push(
+                dim.apply_to(format!("  ({}/{} shown)", height, rows.len()))
+                    .to_string(),
+            );
+        }
+        if let Some(footer) = &self.footer {
+            lines.push(dim.apply_to(format!("  {footer}")).to_string());
+        }
+        lines
+    }
+
+    /// Items that match the query, best first
+    ///
+    /// Every whitespace-separated word in the query must fuzzily match. Items
+    /// whose id starts with the query come before everything else.
+    fn matches(&self, query: &str) -> Vec<Match> {
+        let query = query.trim().to_lowercase();
+        let mut matches = self
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(item_idx, item)| {
+                if query.is_empty() {
+                    return Some(Match {
+                        item: item_idx,
+                        score: 0,
+                        positions: Vec::new(),
+                    });
+                }
+                // lowercase char by char so that positions line up with the displayed text
+                let haystack = item
+                    .haystack()
+                    .chars()
+                    .map(|c| c.to_lowercase().next().unwrap_or(c))
+                    .collect::<Vec<_>>();
+                let mut score = 0;
+                let mut positions = Vec::new();
+                if item.id.starts_with(&query) {
+                    score += 1_000_000;
+                }
+                for word in query.split_whitespace() {
+                    match fuzzy_match(word, &haystack) {
+                        Some((word_score, word_positions)) => {
+                            score += word_score;
+                            positions.extend(word_positions);
+                        }
+                        None if score >= 1_000_000 => {}
+                        None => return None,
+                    }
+                }
+                Some(Match {
+                    item: item_idx,
+                    score,
+                    positions,
+                })
+            })
+            .collect::<Vec<_>>();
+        // stable, so ties stay in list order
+        matches.sort_by_key(|m| -m.score);
+        matches
+    }
+}
+
+/// Match the chars of `needle` in order somewhere in `haystack`
+///
+/// Returns a score, where higher is better, and the positions of the matched
+/// chars. Consecutive chars and chars at the start of words score higher.
+fn fuzzy_match(needle: &str, haystack: &[char]) -> Option<(i64, Vec<usize>)> {
+    let needle = needle.chars().collect::<Vec<_>>();
+    let first = *needle.first()?;
+    let mut best: Option<(i64, Vec<usize>)> = None;
+    for start in (0..haystack.len()).filter(|i| haystack[*i] == first) {
+        let mut positions = vec![start];
+        let mut pos = start;
+        for ch in &needle[1..] {
+            match (pos + 1..haystack.len()).find(|i| haystack[*i] == *ch) {
+                Some(found) => {
+                    positions.push(found);
+                    pos = found;
+                }
+                None => break,
+            }
+        }
+        if positions.len() != needle.len() {
+            // later starts can't match either
+            break;
+        }
+        let score = positions
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let consecutive = i > 0 && positions[i - 1] + 1 == *p;
+                let word_start = *p == 0 || !haystack[*p - 1].is_alphanumeric();
+                1 + if consecutive { 5 } else { 0 } + if word_start { 3 } else { 0 }
+            })
+            .sum::<i64>()
+            - (positions[positions.len() - 1] - start) as i64 / 4;
+        if best.as_ref().map(|(s, _)| score > *s).unwrap_or(true) {
+            best = Some((score, positions));
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str, summary: &str) -> Item {
+        Item::new(id.to_owned())
+            .field(id, Style::new())
+            .field(summary, Style::new())
+    }
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    fn matched_items(select: &FuzzySelect, query: &str) -> Vec<usize> {
+        select.matches(query).iter().map(|m| m.item).collect()
+    }
+
+    #[test]
+    fn fuzzy_match_finds_chars_in_order() {
+        assert_eq!(fuzzy_match("fx", &chars("fix")).unwrap().1, vec![0, 2]);
+        assert!(fuzzy_match("xf", &chars("fix")).is_none());
+        assert!(fuzzy_match("", &chars("fix")).is_none());
+    }
+
+    #[test]
+    fn fuzzy_match_prefers_consecutive_chars_at_word_starts() {
+        let (_, positions) = fuzzy_match("ab", &chars("xaxb ab")).unwrap();
+        assert_eq!(positions, vec![5, 6]);
+        let (word_start, _) = fuzzy_match("par", &chars("fix parser")).unwrap();
+        let (mid_word, _) = fuzzy_match("par", &chars("compare")).unwrap();
+        assert!(word_start > mid_word);
+    }
+
+    #[test]
+    fn hash_prefix_ranks_first() {
+        let items = [
+            item("1234abc", "abc in the summary"),
+            item("abc1234", "unrelated"),
+        ];
+        let select = FuzzySelect::new("", &items);
+        assert_eq!(matched_items(&select, "abc"), vec![1, 0]);
+        assert_eq!(matched_items(&select, "ABC12"), vec![1]);
+    }
+
+    #[test]
+    fn every_word_must_match() {
+        let items = [
+            item("1111111", "fix parser"),
+            item("2222222", "parser cleanup"),
+            item("3333333", "fix lexer"),
+        ];
+        let select = FuzzySelect::new("", &items);
+        assert_eq!(matched_items(&select, "fix par"), vec![0]);
+        assert_eq!(matched_items(&select, "lexer fix"), vec![2]);
+        assert_eq!(matched_items(&select, "  "), vec![0, 1, 2]);
+        assert!(matched_items(&select, "fix cleanup").is_empty());
+    }
+
+    #[test]
+    fn highlight_positions_line_up_with_displayed_text() {
+        let items = [item("abc1234", "Fix bug")];
+        let select = FuzzySelect::new("", &items);
+        let matches = select.matches("FIX bug");
+        let mut positions = matches[0].positions.clone();
+        positions.sort();
+        // fields are displayed joined by a space: "abc1234 Fix bug"
+        assert_eq!(positions, vec![8, 9, 10, 12, 13, 14]);
+    }
+}
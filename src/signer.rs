@@ -0,0 +1,159 @@
+//! mod signer signs rewritten commits the way that `git commit` would, using `gpg` or `ssh-keygen`
+
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::Context as _;
+use anyhow::{anyhow, bail};
+use git2::{Commit, Oid, Repository, Signature, Tree};
+
+/// Signs commits according to `commit.gpgsign`, `gpg.format` and `user.signingkey`
+pub(crate) struct Signer {
+    format: Format,
+    program: String,
+    key: String,
+}
+
+enum Format {
+    OpenPgp,
+    X509,
+    Ssh,
+}
+
+impl Signer {
+    /// The configured signer, or `None` if `commit.gpgsign` is off
+    pub(crate) fn from_config(repo: &Repository) -> Result<Option<Signer>, anyhow::Error> {
+        let cfg = repo.config()?;
+        if !cfg.get_bool("commit.gpgsign").unwrap_or(false) {
+            return Ok(None);
+        }
+        let format = match cfg.get_string("gpg.format").as_deref() {
+            Err(_) | Ok("openpgp") => Format::OpenPgp,
+            Ok("x509") => Format::X509,
+            Ok("ssh") => Format::Ssh,
+            Ok(other) => bail!("unsupported gpg.format {other:?}"),
+        };
+        let (program_setting, default_program) = match format {
+            Format::OpenPgp => ("gpg.openpgp.program", "gpg"),
+            Format::X509 => ("gpg.x509.program", "gpgsm"),
+            Format::Ssh => ("gpg.ssh.program", "ssh-keygen"),
+        };
+        let program = cfg
+            .get_string(program_setting)
+            .or_else(|_| match format {
+                Format::OpenPgp => cfg.get_string("gpg.program"),
+                _ => Err(git2::Error::from_str("no program configured")),
+            })
+            .unwrap_or_else(|_| default_program.to_owned());
+        let key = match (cfg.get_string("user.signingkey"), &format) {
+            (Ok(key), _) => key,
+            (Err(_), Format::Ssh) => bail!("user.signingkey must be set to sign commits with ssh"),
+            // gpg looks up the key for the committer
+            (Err(_), _) => {
+                let sig = repo.signature()?;
+                format!(
+                    "{} <{}>",
+                    sig.name().unwrap_or_default(),
+                    sig.email().unwrap_or_default()
+                )
+            }
+        };
+        Ok(Some(Signer {
+            format,
+            program,
+            key,
+        }))
+    }
+
+    /// Make sure that signing works before anything is rewritten
+    pub(crate) fn check(&self, repo: &Repository) -> Result<(), anyhow::Error> {
+        self.sign(repo, "git-instafix signing check\n").map(|_| ())
+    }
+
+    /// Create a detached signature for `content`
+    fn sign(&self, repo: &Repository, content: &str) -> Result<String, anyhow::Error> {
+        let mut cmd = Command::new(&self.program);
+        // ssh-keygen needs the key in a file, which must be removed afterwards
+        let mut key_file: Option<PathBuf> = None;
+        match self.format {
+            Format::OpenPgp | Format::X509 => {
+                cmd.args(["--status-fd=2", "-bsau", &self.key]);
+            }
+            Format::Ssh => {
+                cmd.args(["-Y", "sign", "-n", "git", "-f"]);
+                // the key can be given literally instead of as a path
+                let literal = match self.key.strip_prefix("key::") {
+                    Some(literal) => Some(literal),
+                    None => self.key.starts_with("ssh-").then_some(self.key.as_str()),
+                };
+                if let Some(literal) = literal {
+                    let path = repo.path().join("instafix-signing-key.pub");
+                    std::fs::write(&path, literal).context("writing signing key")?;
+                    cmd.arg(&path).arg("-U");
+                    key_file = Some(path);
+                } else {
+                    cmd.arg(expand_home(&self.key));
+                }
+            }
+        }
+        let output = run_with_stdin(cmd, content);
+        if let Some(path) = key_file {
+            let _ = std::fs::remove_file(path);
+        }
+        let output = output.with_context(|| format!("running {}", self.program))?;
+        if !output.status.success() {
+            bail!(
+                "{} failed to sign the commit:\n{}",
+                self.program,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        let signature =
+            String::from_utf8(output.stdout).context("reading signature from signing program")?;
+        if signature.is_empty() {
+            bail!("{} produced an empty signature", self.program);
+        }
+        Ok(signature)
+    }
+}
+
+fn run_with_stdin(mut cmd: Command, stdin: &str) -> Result<std::process::Output, anyhow::Error> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("unable to write to signing program"))?
+        .write_all(stdin.as_bytes())?;
+    Ok(child.wait_with_output()?)
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Create a commit that no ref points at, signed if there is a signer
+pub(crate) fn commit(
+    repo: &Repository,
+    signer: Option<&Signer>,
+    author: &Signature<'_>,
+    committer: &Signature<'_>,
+    message: &str,
+    tree: &Tree<'_>,
+    parents: &[&Commit<'_>],
+) -> Result<Oid, anyhow::Error> {
+    let Some(signer) = signer else {
+        return Ok(repo.commit(None, author, committer, message, tree, parents)?);
+    };
+    let buf = repo.commit_create_buffer(author, committer, message, tree, parents)?;
+    let content = std::str::from_utf8(&buf).context("commit is not valid utf-8")?;
+    let signature = signer.sign(repo, content)?;
+    Ok(repo.commit_signed(content, &signature, None)?)
+}
@@ -0,0 +1,177 @@
+//! mod worktrees keeps branches that are checked out in other worktrees from moving underneath them
+
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context as _;
+use git2::build::CheckoutBuilder;
+use git2::{Oid, Repository, Status};
+
+use crate::config::WorktreeBranches;
+use crate::hooks;
+use crate::rebaser::Fixup;
+
+/// A branch that is checked out in a worktree other than the current one
+pub(crate) struct CheckedOut {
+    /// The full name of the branch, like `refs/heads/main`
+    pub(crate) branch: String,
+    pub(crate) path: PathBuf,
+}
+
+impl CheckedOut {
+    fn short_name(&self) -> &str {
+        self.branch
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&self.branch)
+    }
+}
+
+/// Every branch checked out in another worktree of `repo`, including the main one
+pub(crate) fn checked_out_elsewhere(repo: &Repository) -> Result<Vec<CheckedOut>, anyhow::Error> {
+    let Some(here) = repo.workdir() else {
+        return Ok(vec![]);
+    };
+    let here = here.canonicalize()?;
+    let main = Repository::open(hooks::common_dir(repo)?).context("opening main worktree")?;
+    let mut worktrees = vec![];
+    for name in main.worktrees()?.iter().flatten() {
+        // worktrees whose directory has gone away can't have anything checked out
+        let Ok(worktree) = main.find_worktree(name) else {
+            continue;
+        };
+        if let Ok(wt_repo) = Repository::open_from_worktree(&worktree) {
+            worktrees.push(wt_repo);
+        }
+    }
+    worktrees.push(main);
+
+    let mut checked_out = vec![];
+    for wt_repo in worktrees {
+        let Some(path) = wt_repo.workdir() else {
+            continue;
+        };
+        let path = path.canonicalize()?;
+        if path == here {
+            continue;
+        }
+        let head = wt_repo.find_reference("HEAD")?;
+        if let Some(branch) = head.symbolic_target() {
+            checked_out.push(CheckedOut {
+                branch: branch.to_owned(),
+                path,
+            });
+        }
+    }
+    Ok(checked_out)
+}
+
+/// Decide what to do with branches in other worktrees that amending the `fixups` targets would move
+///
+/// Returns the branches to leave where they are. Fails if `mode` refuses to
+/// move them, or if updating one would overwrite uncommitted changes in its
+/// worktree.
+pub(crate) fn check(
+    repo: &Repository,
+    mode: &WorktreeBranches,
+    fixups: &[Fixup],
+) -> Result<Vec<String>, anyhow::Error> {
+    let head = repo.head()?.peel_to_commit()?.id();
+    let mut skipped = vec![];
+    for checked_out in checked_out_elsewhere(repo)? {
+        let Ok(oid) = repo.refname_to_id(&checked_out.branch) else {
+            continue;
+        };
+        let is_rewritten = (oid == head || repo.graph_descendant_of(head, oid)?)
+            && fixups.iter().any(|f| {
+                let target = f.target.id();
+                oid == target || repo.graph_descendant_of(oid, target).unwrap_or(false)
+            });
+        if !is_rewritten {
+            continue;
+        }
+        let name = checked_out.short_name();
+        let path = checked_out.path.display();
+        match mode {
+            WorktreeBranches::Skip => {
+                eprintln!("Warning: not updating branch {name}, it is checked out in {path}");
+                skipped.push(checked_out.branch.clone());
+            }
+            WorktreeBranches::Refuse => bail!(
+                "Branch {name} is checked out in {path}, not rewriting it\n\
+                Use --worktree-branches=skip to leave it where it is, or \
+                --worktree-branches=update to update that worktree too"
+            ),
+            WorktreeBranches::Update => {
+                let wt_repo = Repository::open(&checked_out.path)?;
+                if let Some(dirty) = dirty_path(&wt_repo, fixups)? {
+                    bail!(
+                        "Branch {name} is checked out in {path}, which has uncommitted changes to {dirty}\n\
+                        Commit or stash them there first, or use --worktree-branches=skip"
+                    );
+                }
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+/// The first file changed by the `fixups` that is also changed in the worktree of `wt_repo`
+fn dirty_path(wt_repo: &Repository, fixups: &[Fixup]) -> Result<Option<String>, anyhow::Error> {
+    let statuses = wt_repo.statuses(None)?;
+    for fixup in fixups {
+        for delta in fixup.diff.deltas() {
+            for path in [delta.old_file().path(), delta.new_file().path()]
+                .into_iter()
+                .flatten()
+            {
+                let dirty = statuses.iter().any(|s| {
+                    s.path() == path.to_str()
+                        && !s.status().intersects(Status::CURRENT | Status::IGNORED)
+                });
+                if dirty {
+                    return Ok(Some(path.display().to_string()));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Bring the index and files of the worktree that has `branch` checked out up to date after it moved
+///
+/// Only the files that differ between `from` and `to` are touched, and `check`
+/// made sure that the worktree doesn't have changes to them.
+pub(crate) fn sync(
+    repo: &Repository,
+    branch: &str,
+    from: Oid,
+    to: Oid,
+) -> Result<(), anyhow::Error> {
+    let Some(checked_out) = checked_out_elsewhere(repo)?
+        .into_iter()
+        .find(|c| c.branch == branch)
+    else {
+        return Ok(());
+    };
+    let wt_repo = Repository::open(&checked_out.path)?;
+    let old = wt_repo.find_commit(from)?.tree()?;
+    let new = wt_repo.find_commit(to)?;
+    let diff = wt_repo.diff_tree_to_tree(Some(&old), Some(&new.tree()?), None)?;
+    if diff.deltas().len() == 0 {
+        return Ok(());
+    }
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    for delta in diff.deltas() {
+        for path in [delta.old_file().path(), delta.new_file().path()]
+            .into_iter()
+            .flatten()
+        {
+            checkout.path(path);
+        }
+    }
+    wt_repo
+        .checkout_tree(new.as_object(), Some(&mut checkout))
+        .with_context(|| format!("updating worktree {}", checked_out.path.display()))?;
+    Ok(())
+}
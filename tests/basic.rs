@@ -487,9 +487,1569 @@ new
     assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
 }
 
+#[test]
+fn absorb_distributes_hunks() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    td.child("one.txt").write_str("1\n2\n3\n4\n5\n").unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "-m", "one"], &td);
+    git(&["branch", "intermediate"], &td);
+    td.child("two.txt").write_str("1\n2\n3\n4\n5\n").unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "-m", "two"], &td);
+    git_commits(&["c"], &td);
+
+    td.child("one.txt")
+        .write_str("1\n2\nthree\n4\n5\n")
+        .unwrap();
+    td.child("two.txt").write_str("1\n2\n3\n4\nfive\n").unwrap();
+    td.child("new").touch().unwrap();
+    git(&["add", "-A"], &td);
+
+    let assertion = fixup(&td).arg("--absorb").assert().success();
+    let err = string(assertion.get_output().stderr.clone());
+    assert!(
+        err.contains("new (no existing lines to blame)"),
+        "err: {}",
+        err
+    );
+
+    let (files, err) = git_changed_files("one", &td);
+    assert_eq!(files, "one.txt\n", "out: {} err: {}", files, err);
+    let (files, err) = git_changed_files("two", &td);
+    assert_eq!(files, "two.txt\n", "out: {} err: {}", files, err);
+    assert_eq!(
+        string(git_out(&["show", "intermediate:one.txt"], &td).stdout),
+        "1\n2\nthree\n4\n5\n"
+    );
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:two.txt"], &td).stdout),
+        "1\n2\n3\n4\nfive\n"
+    );
+
+    let expected = "\
+* c HEAD -> changes
+* two
+* one intermediate
+* b main
+* a
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+
+    // the unassigned hunk is still staged
+    let staged = string(git_out(&["diff", "--cached", "--name-only"], &td).stdout);
+    assert_eq!(staged, "new\n");
+}
+
+#[test]
+fn absorb_skips_earlier_hunk_in_same_file() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    let lines = |range: std::ops::RangeInclusive<u32>| -> String {
+        range.map(|n| format!("{n}\n")).collect()
+    };
+    td.child("f").write_str(&lines(1..=10)).unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "-m", "base"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    td.child("f").write_str(&lines(1..=20)).unwrap();
+    git(&["commit", "-am", "A"], &td);
+    git_commits(&["c"], &td);
+
+    let changed = lines(1..=20)
+        .replacen("\n3\n", "\n3\ninserted\n", 1)
+        .replace("18\n", "eighteen\n");
+    td.child("f").write_str(&changed).unwrap();
+    git(&["add", "f"], &td);
+    fixup(&td).args(["--absorb", "--yes"]).assert().success();
+
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:f"], &td).stdout),
+        lines(1..=20).replace("18\n", "eighteen\n")
+    );
+    // the hunk that only upstream lines were blamed for stays staged
+    let staged = string(git_out(&["diff", "--cached"], &td).stdout);
+    assert!(staged.contains("+inserted"), "staged: {}", staged);
+    assert!(!staged.contains("eighteen"), "staged: {}", staged);
+}
+
+#[test]
+fn fixup_lines_moved_by_later_commits() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    let lines = (1..=20).map(|n| format!("{n}\n")).collect::<String>();
+    td.child("f").write_str(&lines).unwrap();
+    git(&["add", "f"], &td);
+    git(&["commit", "-m", "A"], &td);
+    let prepended = format!("p1\np2\np3\np4\np5\n{lines}");
+    td.child("f").write_str(&prepended).unwrap();
+    git(&["commit", "-am", "B"], &td);
+
+    // line 15 of HEAD is line 10 of A
+    td.child("f")
+        .write_str(&prepended.replace("\n10\n", "\nten\n"))
+        .unwrap();
+    git(&["add", "f"], &td);
+    fixup(&td).args(["--absorb", "--yes"]).assert().success();
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:f"], &td).stdout),
+        lines.replace("\n10\n", "\nten\n")
+    );
+    assert_eq!(
+        string(git_out(&["show", "HEAD:f"], &td).stdout),
+        prepended.replace("\n10\n", "\nten\n")
+    );
+
+    // choosing the commit directly goes through the same path
+    td.child("f")
+        .write_str(
+            &prepended
+                .replace("\n10\n", "\nten\n")
+                .replace("\n12\n", "\ntwelve\n"),
+        )
+        .unwrap();
+    git(&["add", "f"], &td);
+    fixup(&td).args(["--commit", "HEAD~"]).assert().success();
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:f"], &td).stdout),
+        lines
+            .replace("\n10\n", "\nten\n")
+            .replace("\n12\n", "\ntwelve\n")
+    );
+    assert_eq!(git_worktree_changed_files(&td), "");
+}
+
+#[test]
+fn stop_on_conflict_when_fixup_conflicts_with_later_commits() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    let lines = (1..=20).map(|n| format!("{n}\n")).collect::<String>();
+    td.child("f").write_str(&lines).unwrap();
+    td.child("g").write_str("1\n2\n3\n4\n5\n6\n7\n").unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "-m", "A"], &td);
+    td.child("f")
+        .write_str(&format!("p1\np2\n{lines}"))
+        .unwrap();
+    git(&["commit", "-am", "B"], &td);
+    td.child("g").write_str("1\n2\n3\n4\nfive\n6\n7\n").unwrap();
+    git(&["commit", "-am", "C"], &td);
+
+    td.child("f")
+        .write_str(&format!("p1\np2\n{}", lines.replace("\n10\n", "\nten\n")))
+        .unwrap();
+    td.child("g")
+        .write_str("1\n2\n3\n4\nfive\nsix\n7\n")
+        .unwrap();
+    git(&["add", "-A"], &td);
+    let assertion = fixup(&td)
+        .args(["--stop-on-conflict", "--commit", "HEAD~2"])
+        .assert()
+        .failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("Stopped at a conflict while applying"),
+        "out: {}",
+        out
+    );
+    assert!(out.contains(" A\n"), "out: {}", out);
+    let g = std::fs::read_to_string(td.path().join("g")).unwrap();
+    assert!(g.contains(">>>>>>> your changes"), "g: {}", g);
+
+    // the fixup isn't applied a second time when carrying on
+    td.child("g").write_str("1\n2\n3\n4\n5\nsix\n7\n").unwrap();
+    git(&["add", "g"], &td);
+    fixup(&td).arg("--continue").assert().failure();
+    td.child("g")
+        .write_str("1\n2\n3\n4\nfive\nsix\n7\n")
+        .unwrap();
+    git(&["add", "g"], &td);
+    fixup(&td).arg("--continue").assert().success();
+
+    assert_eq!(
+        string(git_out(&["show", "HEAD~2:f"], &td).stdout),
+        lines.replace("\n10\n", "\nten\n")
+    );
+    assert_eq!(
+        string(git_out(&["show", "HEAD~2:g"], &td).stdout),
+        "1\n2\n3\n4\n5\nsix\n7\n"
+    );
+    let expected = "\
+* C HEAD -> changes
+* B
+* A
+* a main
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+    assert_eq!(git_worktree_changed_files(&td), "");
+}
+
+#[test]
+fn select_commit_by_rev() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "d", "e"], &td);
+
+    td.child("new").touch().unwrap();
+    git(&["add", "new"], &td);
+    fixup(&td).args(["--commit", "HEAD~2"]).assert().success();
+    let (files, err) = git_changed_files("target", &td);
+    assert_eq!(files, "file_target\nnew\n", "out: {} err: {}", files, err);
+
+    td.child("new-by-message").touch().unwrap();
+    git(&["add", "new-by-message"], &td);
+    fixup(&td).args(["--commit", ":/^d"]).assert().success();
+    let (files, err) = git_changed_files("d", &td);
+    assert_eq!(
+        files, "file_d\nnew-by-message\n",
+        "out: {} err: {}",
+        files, err
+    );
+
+    // commits before the upstream merge-base can't be amended
+    td.child("too-old").touch().unwrap();
+    git(&["add", "too-old"], &td);
+    let assertion = fixup(&td).args(["--commit", "main"]).assert().failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("is not after the upstream merge-base"),
+        "out: {}",
+        out
+    );
+}
+
+#[test]
+fn non_interactive_fails_instead_of_prompting() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "d"], &td);
+
+    // nothing staged
+    td.child("file_target").write_str("changed").unwrap();
+    let assertion = fixup(&td)
+        .args(["--non-interactive", "-P", "target"])
+        .assert()
+        .code(3);
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(out.contains("use --all"), "out: {}", out);
+
+    // no way to choose a commit
+    git(&["add", "file_target"], &td);
+    let assertion = fixup(&td).arg("--yes").assert().code(3);
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(out.contains("--commit"), "out: {}", out);
+
+    git(&["reset"], &td);
+    fixup(&td)
+        .args(["--yes", "--all", "-P", "target"])
+        .assert()
+        .success();
+    let (files, err) = git_changed_files("target", &td);
+    assert_eq!(files, "file_target\n", "out: {} err: {}", files, err);
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:file_target"], &td).stdout),
+        "changed"
+    );
+}
+
+#[test]
+fn dry_run_changes_nothing() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "intermediate"], &td);
+    git_commits(&["target", "c"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["d"], &td);
+
+    td.child("new").touch().unwrap();
+    td.child("file_d").write_str("unstaged").unwrap();
+    git(&["add", "new"], &td);
+    let before = string(git_out(&["show-ref", "--head"], &td).stdout);
+    let status_before = string(git_out(&["status", "--porcelain"], &td).stdout);
+
+    let assertion = fixup(&td)
+        .args(["--dry-run", "-P", "target"])
+        .assert()
+        .success();
+    let out = string(assertion.get_output().stdout.clone());
+    let steps = out
+        .lines()
+        .skip_while(|l| !l.starts_with("Dry run"))
+        .skip(1)
+        .take(3)
+        .map(|l| l.split_whitespace().take(3).collect::<Vec<_>>())
+        .map(|words| (words[0], words[2]))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        steps,
+        [("amend", "target"), ("rewrite", "c"), ("rewrite", "d")],
+        "out: {}",
+        out
+    );
+    assert!(
+        out.contains("would update branch intermediate"),
+        "out: {}",
+        out
+    );
+    assert!(out.contains("would update branch changes"), "out: {}", out);
+
+    assert_eq!(string(git_out(&["show-ref", "--head"], &td).stdout), before);
+    assert_eq!(
+        string(git_out(&["status", "--porcelain"], &td).stdout),
+        status_before
+    );
+    assert_eq!(string(git_out(&["stash", "list"], &td).stdout), "");
+}
+
+#[test]
+fn undo_restores_every_moved_branch() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "intermediate"], &td);
+    git_commits(&["target", "c"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["d"], &td);
+
+    td.child("new").touch().unwrap();
+    git(&["add", "new"], &td);
+    let before = string(git_out(&["show-ref", "--heads"], &td).stdout);
+
+    fixup(&td).args(["-P", "target"]).assert().success();
+    assert_ne!(
+        string(git_out(&["show-ref", "--heads"], &td).stdout),
+        before
+    );
+
+    let assertion = fixup(&td).arg("undo").assert().success();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("restored refs/heads/intermediate"),
+        "out: {}",
+        out
+    );
+    assert!(out.contains("restored refs/heads/changes"), "out: {}", out);
+    assert_eq!(
+        string(git_out(&["show-ref", "--heads"], &td).stdout),
+        before
+    );
+    // the changes that were applied are staged again
+    let staged = string(git_out(&["diff", "--cached", "--name-only"], &td).stdout);
+    assert_eq!(staged, "new\n");
+
+    let assertion = fixup(&td).arg("undo").assert().failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("No instafix operations to undo"),
+        "out: {}",
+        out
+    );
+}
+
+#[test]
+fn rewrites_without_touching_worktree() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "d"], &td);
+
+    td.child("file_target").write_str("staged").unwrap();
+    git(&["add", "file_target"], &td);
+    td.child("file_d").write_str("unstaged").unwrap();
+    let modified = |name: &str| {
+        std::fs::metadata(td.child(name).path())
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    let before = (modified("file_d"), modified("file_target"));
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    fixup(&td).args(["-P", "target"]).assert().success();
+
+    assert_eq!((modified("file_d"), modified("file_target")), before);
+    assert_eq!(string(git_out(&["stash", "list"], &td).stdout), "");
+    assert_eq!(
+        string(git_out(&["status", "--porcelain"], &td).stdout),
+        " M file_d\n"
+    );
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:file_target"], &td).stdout),
+        "staged"
+    );
+}
+
+#[test]
+fn conflict_changes_nothing() {
+    let td = assert_fs::TempDir::new().unwrap();
+    conflicting_history(&td);
+    let before = string(git_out(&["show-ref", "--heads"], &td).stdout);
+
+    let assertion = fixup(&td).args(["-P", "target"]).assert().failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(out.contains("nothing has been changed"), "out: {}", out);
+
+    assert_eq!(
+        string(git_out(&["show-ref", "--heads"], &td).stdout),
+        before
+    );
+    let staged = string(git_out(&["diff", "--cached"], &td).stdout);
+    assert!(staged.contains("+six"), "staged: {}", staged);
+}
+
+#[test]
+fn keeps_merge_commits() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a"], &td);
+    git(&["tag", "base"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git_commits(&["target"], &td);
+    td.child("shared.txt").write_str("changes\n").unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "-m", "c"], &td);
+    git(&["checkout", "main"], &td);
+    td.child("shared.txt").write_str("main\n").unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "-m", "m"], &td);
+    git(&["checkout", "changes"], &td);
+    git_out(&["merge", "main"], &td);
+    td.child("shared.txt").write_str("resolved\n").unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "--no-edit"], &td);
+    git_commits(&["d"], &td);
+
+    let expected = "\
+* d HEAD -> changes
+*   Merge branch 'main' into changes
+|\\
+| * m main
+* | c
+* | target
+|/
+* a tag: base
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+    let main = string(git_out(&["rev-parse", "main"], &td).stdout);
+
+    td.child("file_target").write_str("fixed").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args(["--commit", ":/target", "-u", "base"])
+        .assert()
+        .success();
+
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+    assert_eq!(string(git_out(&["rev-parse", "main"], &td).stdout), main);
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:shared.txt"], &td).stdout),
+        "resolved\n"
+    );
+    assert_eq!(
+        string(git_out(&["show", "HEAD~~~:file_target"], &td).stdout),
+        "fixed"
+    );
+    assert_eq!(git_worktree_changed_files(&td), "");
+}
+
+/// A branch where changing line 6 of `lines.txt` conflicts with the later
+/// commits "change" and "revert"
+fn conflicting_history(td: &assert_fs::TempDir) {
+    git_init(td);
+    git_commits(&["a"], td);
+    git(&["checkout", "-b", "changes"], td);
+    git(&["branch", "-u", "main"], td);
+    let lines = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+    td.child("lines.txt").write_str(lines).unwrap();
+    git(&["add", "-A"], td);
+    git(&["commit", "-m", "target"], td);
+    td.child("lines.txt")
+        .write_str(&lines.replace('5', "five"))
+        .unwrap();
+    git(&["commit", "-am", "change"], td);
+    td.child("lines.txt").write_str(lines).unwrap();
+    git(&["commit", "-am", "revert"], td);
+
+    td.child("lines.txt")
+        .write_str(&lines.replace('6', "six"))
+        .unwrap();
+    git(&["add", "lines.txt"], td);
+}
+
+#[test]
+fn stop_on_conflict_and_continue() {
+    let td = assert_fs::TempDir::new().unwrap();
+    conflicting_history(&td);
+    td.child("file_a").write_str("unstaged").unwrap();
+    write_hook(&td, "post-rewrite", "cat > .git/rewritten");
+    git(&["notes", "add", "-m", "revert note", "HEAD"], &td);
+    git(&["config", "notes.rewriteRef", "refs/notes/commits"], &td);
+
+    let assertion = fixup(&td)
+        .args(["--stop-on-conflict", "-P", "target"])
+        .assert()
+        .failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("Stopped at a conflict while applying"),
+        "out: {}",
+        out
+    );
+    assert!(out.contains("change"), "out: {}", out);
+
+    // a new run can't start until this one is finished
+    fixup(&td).args(["-P", "target"]).assert().failure();
+    // conflicts have to be resolved first
+    fixup(&td).arg("--continue").assert().failure();
+
+    td.child("lines.txt")
+        .write_str("1\n2\n3\n4\nfive\nsix\n7\n8\n9\n")
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    let assertion = fixup(&td).arg("--continue").assert().failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(out.contains("revert"), "out: {}", out);
+
+    td.child("lines.txt")
+        .write_str("1\n2\n3\n4\n5\nsix\n7\n8\n9\n")
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    fixup(&td).arg("--continue").assert().success();
+
+    let expected = "\
+* revert HEAD -> changes
+* change
+* target
+* a main
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+    assert_eq!(
+        string(git_out(&["show", "HEAD~2:lines.txt"], &td).stdout),
+        "1\n2\n3\n4\n5\nsix\n7\n8\n9\n"
+    );
+    assert_eq!(git_worktree_changed_files(&td), "file_a\n");
+    let rewritten = std::fs::read_to_string(td.path().join(".git/rewritten")).unwrap();
+    let new = rewritten
+        .lines()
+        .map(|l| l.split(' ').nth(1).unwrap())
+        .collect::<Vec<_>>();
+    let expected = string(git_out(&["rev-parse", "HEAD~2", "HEAD~", "HEAD"], &td).stdout);
+    assert_eq!(new, expected.lines().collect::<Vec<_>>());
+    assert_eq!(
+        string(git_out(&["notes", "show", "HEAD"], &td).stdout),
+        "revert note\n"
+    );
+}
+
+#[test]
+fn stop_on_conflict_restores_stash() {
+    let td = assert_fs::TempDir::new().unwrap();
+    conflicting_history(&td);
+    // an unrelated stash, to be pushed while stopped
+    td.child("file_a").write_str("other stash").unwrap();
+    git(&["stash", "push", "--", "file_a"], &td);
+    let other_stash = string(git_out(&["rev-parse", "stash@{0}"], &td).stdout);
+    git(&["stash", "drop"], &td);
+
+    td.child("file_a").write_str("unstaged").unwrap();
+    td.child("untracked").write_str("untracked").unwrap();
+    fixup(&td)
+        .args(["--stop-on-conflict", "--include-untracked", "-P", "target"])
+        .assert()
+        .failure();
+    assert!(!td.path().join("untracked").exists());
+    git(&["stash", "store", "-m", "other", other_stash.trim()], &td);
+
+    td.child("lines.txt")
+        .write_str("1\n2\n3\n4\nfive\nsix\n7\n8\n9\n")
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    fixup(&td).arg("--continue").assert().failure();
+    td.child("lines.txt")
+        .write_str("1\n2\n3\n4\n5\nsix\n7\n8\n9\n")
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    fixup(&td).arg("--continue").assert().success();
+
+    assert_eq!(
+        std::fs::read_to_string(td.path().join("untracked")).unwrap(),
+        "untracked"
+    );
+    let status = string(git_out(&["status", "--porcelain"], &td).stdout);
+    assert_eq!(status, " M file_a\n?? untracked\n");
+    // only our own stash was popped
+    let stashes = string(git_out(&["stash", "list", "--format=%H"], &td).stdout);
+    assert_eq!(stashes, other_stash);
+
+    // a stash that can't be put back is kept, with instructions
+    td.child("lines.txt")
+        .write_str("1\n2\n3\nfour\n5\nsix\n7\n8\n9\n")
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    fixup(&td)
+        .args(["--stop-on-conflict", "-P", "target"])
+        .assert()
+        .failure();
+    td.child("lines.txt")
+        .write_str("1\n2\n3\nfour\nfive\nsix\n7\n8\n9\n")
+        .unwrap();
+    td.child("file_a").write_str("resolved").unwrap();
+    git(&["add", "lines.txt", "file_a"], &td);
+    fixup(&td).arg("--continue").assert().failure();
+    td.child("lines.txt")
+        .write_str("1\n2\n3\nfour\n5\nsix\n7\n8\n9\n")
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    let assertion = fixup(&td).arg("--continue").assert().failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("uncommitted changes conflict with the rewritten commits"),
+        "out: {}",
+        out
+    );
+    assert!(out.contains("git stash drop stash@{0}"), "out: {}", out);
+    let stashes = string(git_out(&["stash", "list"], &td).stdout);
+    assert_eq!(stashes.lines().count(), 2, "stashes: {}", stashes);
+    let status = string(git_out(&["status", "--porcelain"], &td).stdout);
+    assert!(status.contains("UU file_a"), "status: {}", status);
+}
+
+#[test]
+fn failed_rebase_restores_stash() {
+    let td = assert_fs::TempDir::new().unwrap();
+    conflicting_history(&td);
+    git(&["rm", "-q", "file_a"], &td);
+    git(&["commit", "-m", "remove file_a", "--", "file_a"], &td);
+    // the rebase fails because checking out the older commits would overwrite this
+    td.child("file_a").write_str("untracked").unwrap();
+    td.child("lines.txt")
+        .write_str("1\n2\n3\n4\n5\nsix\n7\n8\n9\nunstaged\n")
+        .unwrap();
+
+    fixup(&td)
+        .args(["--stop-on-conflict", "-P", "target"])
+        .assert()
+        .failure();
+
+    let status = string(git_out(&["status", "--porcelain"], &td).stdout);
+    assert_eq!(status, " M lines.txt\n?? file_a\n");
+    assert!(std::fs::read_to_string(td.path().join("lines.txt"))
+        .unwrap()
+        .ends_with("unstaged\n"));
+    let stashes = string(git_out(&["stash", "list"], &td).stdout);
+    assert_eq!(stashes, "");
+}
+
+#[test]
+fn failed_rebase_explains_how_to_finish() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+    git_commits(&["a"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    let lines = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+    td.child("lines.txt").write_str(lines).unwrap();
+    td.child("removed").write_str("removed").unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "-m", "target"], &td);
+    td.child("lines.txt")
+        .write_str(&lines.replace('5', "five"))
+        .unwrap();
+    git(&["commit", "-am", "change"], &td);
+    td.child("lines.txt").write_str(lines).unwrap();
+    git(&["commit", "-am", "revert"], &td);
+    git(&["rm", "-q", "removed"], &td);
+    git(&["commit", "-m", "remove"], &td);
+    // picking the target fails because it would overwrite this
+    td.child("removed").write_str("untracked").unwrap();
+    td.child("lines.txt")
+        .write_str(&lines.replace('6', "six"))
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+
+    let assertion = fixup(&td)
+        .args(["--stop-on-conflict", "-P", "target"])
+        .assert()
+        .failure();
+    let err = string(assertion.get_output().stderr.clone());
+    let main = string(git_out(&["rev-parse", "main"], &td).stdout);
+    let expected = format!("git rebase --interactive --autosquash {}", main.trim());
+    assert!(err.lines().any(|l| l.trim() == expected), "err: {}", err);
+}
+
+#[test]
+fn stop_on_conflict_from_git_config() {
+    let td = assert_fs::TempDir::new().unwrap();
+    conflicting_history(&td);
+    git(&["config", "instafix.stop-on-conflict", "true"], &td);
+
+    let assertion = fixup(&td).args(["-P", "target"]).assert().failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(out.contains("Stopped at a conflict"), "out: {}", out);
+    fixup(&td).arg("--abort").assert().success();
+
+    // the command line wins over git config
+    let assertion = fixup(&td)
+        .args(["--stop-on-conflict=false", "-P", "target"])
+        .assert()
+        .failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(out.contains("nothing has been changed"), "out: {}", out);
+}
+
+#[test]
+fn include_untracked_from_git_config() {
+    let td = assert_fs::TempDir::new().unwrap();
+    conflicting_history(&td);
+    git(&["config", "instafix.include-untracked", "true"], &td);
+    td.child("untracked").write_str("untracked").unwrap();
+
+    fixup(&td)
+        .args(["--stop-on-conflict", "-P", "target"])
+        .assert()
+        .failure();
+    assert!(!td.path().join("untracked").exists());
+    fixup(&td).arg("--abort").assert().success();
+    assert!(td.path().join("untracked").exists());
+
+    // the command line wins over git config
+    fixup(&td)
+        .args([
+            "--stop-on-conflict",
+            "--include-untracked=false",
+            "-P",
+            "target",
+        ])
+        .assert()
+        .failure();
+    assert!(td.path().join("untracked").exists());
+}
+
+#[test]
+fn stop_on_conflict_and_abort() {
+    let td = assert_fs::TempDir::new().unwrap();
+    conflicting_history(&td);
+    let before = string(git_out(&["show-ref", "--heads"], &td).stdout);
+
+    fixup(&td)
+        .args(["--stop-on-conflict", "-P", "target"])
+        .assert()
+        .failure();
+    fixup(&td).arg("--abort").assert().success();
+
+    assert_eq!(
+        string(git_out(&["show-ref", "--heads"], &td).stdout),
+        before
+    );
+    let staged = string(git_out(&["diff", "--cached"], &td).stdout);
+    assert!(staged.contains("+six"), "staged: {}", staged);
+
+    // uncommitted changes that were stashed are put back too
+    td.child("file_a").write_str("unstaged").unwrap();
+    td.child("untracked").write_str("untracked").unwrap();
+    fixup(&td)
+        .args(["--stop-on-conflict", "--include-untracked", "-P", "target"])
+        .assert()
+        .failure();
+    fixup(&td).arg("--abort").assert().success();
+    let status = string(git_out(&["status", "--porcelain"], &td).stdout);
+    assert_eq!(status, " M file_a\nM  lines.txt\n?? untracked\n");
+    let stashes = string(git_out(&["stash", "list"], &td).stdout);
+    assert_eq!(stashes, "");
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 // Helpers
 
+#[test]
+fn squash_changes_message() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "d"], &td);
+
+    // without a terminal the editor is only opened if it is chosen with GIT_EDITOR
+    td.child("file_target").write_str("fixed").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args(["--squash", "-P", "target"])
+        .env_remove("GIT_EDITOR")
+        .assert()
+        .code(3);
+
+    git(&["config", "core.commentChar", ";"], &td);
+    fixup(&td)
+        .args(["--squash", "-P", "target"])
+        .env("GIT_EDITOR", "sed -i.bak -e 's/^target$/edited target/'")
+        .assert()
+        .success();
+    assert_eq!(
+        string(git_out(&["log", "-1", "--format=%B", "HEAD~"], &td).stdout),
+        "edited target\n\n"
+    );
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:file_target"], &td).stdout),
+        "fixed"
+    );
+
+    td.child("file_target").write_str("fixed again").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args(["--message", "  message target  \n\n\n", "-P", "edited"])
+        .assert()
+        .success();
+    assert_eq!(
+        string(git_out(&["log", "-1", "--format=%B", "HEAD~"], &td).stdout),
+        "  message target\n\n"
+    );
+
+    td.child("message").write_str("file target\n").unwrap();
+    td.child("file_target")
+        .write_str("fixed from file")
+        .unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args(["-F", "message", "-P", "message"])
+        .assert()
+        .success();
+    let expected = "\
+* d HEAD -> changes
+* file target
+* b main
+* a
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+
+    // instafix.squash asks for a new message, unless the command line says otherwise
+    git(&["config", "instafix.squash", "true"], &td);
+    td.child("file_target")
+        .write_str("fixed by config")
+        .unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args(["-P", "file"])
+        .env_remove("GIT_EDITOR")
+        .assert()
+        .code(3);
+    fixup(&td)
+        .args(["--squash=false", "-P", "file"])
+        .env_remove("GIT_EDITOR")
+        .assert()
+        .success();
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+}
+
+#[test]
+fn squash_message_survives_stop_on_conflict() {
+    let td = assert_fs::TempDir::new().unwrap();
+    conflicting_history(&td);
+
+    fixup(&td)
+        .args([
+            "--stop-on-conflict",
+            "--message",
+            "new target",
+            "-P",
+            "target",
+        ])
+        .assert()
+        .failure();
+    td.child("lines.txt")
+        .write_str("1\n2\n3\n4\nfive\nsix\n7\n8\n9\n")
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    fixup(&td).arg("--continue").assert().failure();
+    td.child("lines.txt")
+        .write_str("1\n2\n3\n4\n5\nsix\n7\n8\n9\n")
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    fixup(&td).arg("--continue").assert().success();
+
+    let expected = "\
+* revert HEAD -> changes
+* change
+* new target
+* a main
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+}
+
+#[test]
+fn reword_changes_only_message() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "c", "d"], &td);
+    git(&["branch", "middle", "HEAD~"], &td);
+    let target_tree = string(git_out(&["rev-parse", "HEAD~2^{tree}"], &td).stdout);
+
+    fixup(&td)
+        .args(["reword", "-P", "target"])
+        .env("GIT_EDITOR", "sed -i.bak -e 's/^target$/reworded/'")
+        .assert()
+        .success();
+    let expected = "\
+* d HEAD -> changes
+* c middle
+* reworded
+* b main
+* a
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+    assert_eq!(
+        string(git_out(&["rev-parse", "HEAD~2^{tree}"], &td).stdout),
+        target_tree
+    );
+
+    // staged changes are left alone
+    td.child("file_c").write_str("staged").unwrap();
+    git(&["add", "file_c"], &td);
+    fixup(&td)
+        .args([
+            "reword",
+            "--commit",
+            "HEAD~2",
+            "--message",
+            "reworded again",
+        ])
+        .assert()
+        .success();
+    let expected = "\
+* d HEAD -> changes
+* c middle
+* reworded again
+* b main
+* a
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+    let (files, err) = git_changed_files("c", &td);
+    assert_eq!(files, "file_c\n", "out: {} err: {}", files, err);
+    assert_eq!(
+        string(git_out(&["diff", "--cached", "--name-only"], &td).stdout),
+        "file_c\n"
+    );
+}
+
+#[test]
+fn committer_identity_options() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    for name in ["target", "c", "d"] {
+        td.child(format!("file_{}", name)).touch().unwrap();
+        git(&["add", "-A"], &td);
+        let out = git_inner(&["commit", "-m", name], &td)
+            .env("GIT_AUTHOR_NAME", "author")
+            .env("GIT_AUTHOR_DATE", "2000-01-01T00:00:00Z")
+            .env("GIT_COMMITTER_NAME", "committer")
+            .env("GIT_COMMITTER_DATE", "2001-01-01T00:00:00Z")
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{}", string(out.stderr));
+    }
+    let identities = |td: &assert_fs::TempDir| {
+        string(git_out(&["log", "-3", "--format=%s %an %at %cn %ct"], td).stdout)
+    };
+
+    td.child("file_target").write_str("fixed").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args(["--keep-committer", "-P", "target"])
+        .assert()
+        .success();
+    let out = identities(&td);
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "d author 946684800 committer 978307200", "{out}");
+    assert_eq!(lines[1], "c author 946684800 committer 978307200", "{out}");
+    assert!(
+        lines[2].starts_with("target author 946684800 nobody "),
+        "{out}"
+    );
+    assert!(!lines[2].ends_with(" 978307200"), "{out}");
+
+    // the command line wins over git config
+    git(&["config", "instafix.keep-committer", "true"], &td);
+    td.child("file_target")
+        .write_str("fixed in config")
+        .unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args(["--keep-committer=false", "-P", "target"])
+        .assert()
+        .success();
+    let out = identities(&td);
+    assert!(out.lines().all(|l| l.contains(" nobody ")), "{out}");
+    git(&["config", "--unset", "instafix.keep-committer"], &td);
+
+    td.child("file_target").write_str("fixed again").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args([
+            "--committer-date-is-author-date",
+            "--reset-author",
+            "-P",
+            "target",
+        ])
+        .assert()
+        .success();
+    let out = identities(&td);
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "d author 946684800 nobody 946684800", "{out}");
+    assert_eq!(lines[1], "c author 946684800 nobody 946684800", "{out}");
+    let target = lines[2].split(' ').collect::<Vec<_>>();
+    assert_eq!(target[..2], ["target", "nobody"], "{out}");
+    assert_ne!(target[2], "946684800", "{out}");
+    assert_eq!(target[2], target[4], "{out}");
+}
+
+#[test]
+fn runs_hooks() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "d"], &td);
+
+    write_hook(&td, "pre-commit", "exit 1");
+    td.child("file_target").write_str("fixed").unwrap();
+    git(&["add", "file_target"], &td);
+    let assertion = fixup(&td).args(["-P", "target"]).assert().failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(out.contains("pre-commit hook failed"), "out: {}", out);
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:file_target"], &td).stdout),
+        ""
+    );
+
+    // what the pre-commit hook stages is part of the fixup
+    write_hook(
+        &td,
+        "pre-commit",
+        "printf formatted > file_target && git add file_target",
+    );
+    write_hook(&td, "commit-msg", "printf '\\nHooked: yes\\n' >> \"$1\"");
+    write_hook(
+        &td,
+        "post-rewrite",
+        "echo \"$1\" > .git/rewrite-kind; cat > .git/rewritten",
+    );
+    fixup(&td).args(["-P", "target"]).assert().success();
+    assert_eq!(
+        string(git_out(&["show", "HEAD~:file_target"], &td).stdout),
+        "formatted"
+    );
+    // a plain fixup keeps the target's message
+    assert_eq!(
+        string(git_out(&["log", "-1", "--format=%B", "HEAD~"], &td).stdout),
+        "target\n\n"
+    );
+    let rewritten = std::fs::read_to_string(td.path().join(".git/rewritten")).unwrap();
+    let new = rewritten
+        .lines()
+        .map(|l| l.split(' ').nth(1).unwrap())
+        .collect::<Vec<_>>();
+    let expected = string(git_out(&["rev-parse", "HEAD~", "HEAD"], &td).stdout);
+    assert_eq!(new, expected.lines().collect::<Vec<_>>());
+    assert_eq!(
+        std::fs::read_to_string(td.path().join(".git/rewrite-kind")).unwrap(),
+        "rebase\n"
+    );
+
+    // but a new message goes through the commit-msg hook
+    write_hook(&td, "pre-commit", "exit 0");
+    td.child("file_target").write_str("squashed").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args(["--message", "reworded", "-P", "target"])
+        .assert()
+        .success();
+    assert_eq!(
+        string(git_out(&["log", "-1", "--format=%B", "HEAD~"], &td).stdout),
+        "reworded\n\nHooked: yes\n\n"
+    );
+
+    // none of them run with --no-verify
+    write_hook(&td, "pre-commit", "exit 1");
+    td.child("file_target").write_str("fixed again").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td)
+        .args(["--no-verify", "--message", "unhooked", "-P", "reworded"])
+        .assert()
+        .success();
+    assert_eq!(
+        string(git_out(&["log", "-1", "--format=%B", "HEAD~"], &td).stdout),
+        "unhooked\n\n"
+    );
+}
+
+#[test]
+fn copies_notes() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "d"], &td);
+    git(&["notes", "add", "-m", "target note", "HEAD~"], &td);
+    git(
+        &["notes", "--ref", "ci", "add", "-m", "ci passed", "HEAD"],
+        &td,
+    );
+    git(
+        &["notes", "--ref", "other", "add", "-m", "not copied", "HEAD"],
+        &td,
+    );
+    git(
+        &["config", "--add", "notes.rewriteRef", "refs/notes/commits"],
+        &td,
+    );
+    git(
+        &["config", "--add", "notes.rewriteRef", "refs/notes/c*"],
+        &td,
+    );
+
+    td.child("file_target").write_str("fixed").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td).args(["-P", "target"]).assert().success();
+    assert_eq!(
+        string(git_out(&["notes", "show", "HEAD~"], &td).stdout),
+        "target note\n"
+    );
+    assert_eq!(
+        string(git_out(&["notes", "--ref", "ci", "show", "HEAD"], &td).stdout),
+        "ci passed\n"
+    );
+    assert!(!git_out(&["notes", "--ref", "other", "show", "HEAD"], &td)
+        .status
+        .success());
+
+    // amended commits can be left without their notes
+    git(&["config", "notes.rewrite.amend", "false"], &td);
+    td.child("file_target").write_str("fixed again").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td).args(["-P", "target"]).assert().success();
+    assert!(!git_out(&["notes", "show", "HEAD~"], &td).status.success());
+    assert_eq!(
+        string(git_out(&["notes", "--ref", "ci", "show", "HEAD"], &td).stdout),
+        "ci passed\n"
+    );
+}
+
+#[test]
+fn moves_refs_matching_update_refs() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "d"], &td);
+    git(&["tag", "wip-1", "HEAD~"], &td);
+    git(&["tag", "-a", "-m", "annotated", "wip-2", "HEAD~"], &td);
+    git(&["tag", "release", "HEAD~"], &td);
+    git(&["update-ref", "refs/stack/d", "HEAD"], &td);
+    git(&["config", "instafix.update-refs", "refs/tags/wip-*"], &td);
+    let before = string(git_out(&["show-ref"], &td).stdout);
+    let old_target = string(git_out(&["rev-parse", "HEAD~"], &td).stdout);
+
+    td.child("new").touch().unwrap();
+    git(&["add", "new"], &td);
+    let assertion = fixup(&td)
+        .args(["-P", "target", "--update-refs", "refs/stack/*"])
+        .assert()
+        .success();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(out.contains("updated ref refs/tags/wip-1"), "out: {}", out);
+    assert!(out.contains("updated ref refs/stack/d"), "out: {}", out);
+
+    let rev = |name: &str| string(git_out(&["rev-parse", name], &td).stdout);
+    assert_eq!(rev("wip-1"), rev("HEAD~"));
+    assert_eq!(rev("refs/stack/d"), rev("HEAD"));
+    // annotated tags and tags that don't match are left alone
+    assert_eq!(rev("wip-2^{commit}"), old_target);
+    assert_eq!(rev("release"), old_target);
+
+    fixup(&td).arg("undo").assert().success();
+    assert_eq!(string(git_out(&["show-ref"], &td).stdout), before);
+}
+
+#[test]
+fn branches_checked_out_in_other_worktrees() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "d"], &td);
+    let wt_dir = assert_fs::TempDir::new().unwrap();
+    let wt = wt_dir.path().join("wt");
+    git(
+        &[
+            "worktree",
+            "add",
+            wt.to_str().unwrap(),
+            "-b",
+            "other",
+            "HEAD~",
+        ],
+        &td,
+    );
+    let wt_path = wt.canonicalize().unwrap();
+    let rev = |name: &str| string(git_out(&["rev-parse", name], &td).stdout);
+    let other_before = rev("other");
+
+    // skipped with a warning by default
+    td.child("file_target").write_str("first").unwrap();
+    git(&["add", "file_target"], &td);
+    let assertion = fixup(&td).args(["-P", "target"]).assert().success();
+    let err = string(assertion.get_output().stderr.clone());
+    assert!(
+        err.contains(&format!(
+            "not updating branch other, it is checked out in {}",
+            wt_path.display()
+        )),
+        "err: {}",
+        err
+    );
+    assert_eq!(rev("other"), other_before);
+
+    // refusing leaves everything alone
+    let new_target = rev("HEAD~");
+    git(
+        &[
+            "-C",
+            wt.to_str().unwrap(),
+            "reset",
+            "--hard",
+            new_target.trim(),
+        ],
+        &td,
+    );
+    let head_before = rev("HEAD");
+    td.child("file_target").write_str("second").unwrap();
+    git(&["add", "file_target"], &td);
+    let assertion = fixup(&td)
+        .args(["-P", "target", "--worktree-branches", "refuse"])
+        .assert()
+        .failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains(&format!("checked out in {}", wt_path.display())),
+        "out: {}",
+        out
+    );
+    assert_eq!(rev("HEAD"), head_before);
+
+    // updating moves the branch and brings the other worktree along, keeping its other changes
+    std::fs::write(wt.join("file_b"), "local change").unwrap();
+    git(&["config", "instafix.worktree-branches", "update"], &td);
+    fixup(&td).args(["-P", "target"]).assert().success();
+    assert_eq!(rev("other"), rev("HEAD~"));
+    assert_eq!(
+        std::fs::read_to_string(wt.join("file_target")).unwrap(),
+        "second"
+    );
+    let status =
+        string(git_out(&["-C", wt.to_str().unwrap(), "status", "--porcelain"], &td).stdout);
+    assert_eq!(status, " M file_b\n");
+
+    // but not if it would overwrite changes there
+    std::fs::write(wt.join("file_target"), "local change").unwrap();
+    td.child("file_target").write_str("third").unwrap();
+    git(&["add", "file_target"], &td);
+    let assertion = fixup(&td).args(["-P", "target"]).assert().failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("which has uncommitted changes to file_target"),
+        "out: {}",
+        out
+    );
+
+    // an invalid setting is an error, not a crash
+    git(&["config", "instafix.worktree-branches", "bogus"], &td);
+    let assertion = fixup(&td).args(["-P", "target"]).assert().code(1);
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("Error: Invalid instafix.worktree-branches"),
+        "out: {}",
+        out
+    );
+    let err = string(assertion.get_output().stderr.clone());
+    assert!(!err.contains("panicked"), "err: {}", err);
+}
+
+#[test]
+fn amends_root_commit() {
+    let td = assert_fs::TempDir::new().unwrap();
+    // no upstream branch, so the whole history can be rewritten
+    git_init_default_branch_name("changes", &td);
+    git_commits(&["target", "b", "c"], &td);
+    git(&["branch", "root", "HEAD~2"], &td);
+    git(&["branch", "middle", "HEAD~"], &td);
+
+    td.child("file_target").write_str("fixed").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td).args(["-P", "target"]).assert().success();
+
+    let expected = "\
+* c HEAD -> changes
+* b middle
+* target root
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+    assert_eq!(
+        string(git_out(&["show", "root:file_target"], &td).stdout),
+        "fixed"
+    );
+    assert_eq!(git_worktree_changed_files(&td), "");
+}
+
+#[test]
+fn stop_on_conflict_after_root_commit() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init_default_branch_name("changes", &td);
+    let lines = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+    td.child("lines.txt").write_str(lines).unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "-m", "target"], &td);
+    git(&["branch", "root"], &td);
+    td.child("lines.txt")
+        .write_str(&lines.replace('5', "five"))
+        .unwrap();
+    git(&["commit", "-am", "change"], &td);
+    td.child("lines.txt").write_str(lines).unwrap();
+    git(&["commit", "-am", "revert"], &td);
+    td.child("lines.txt")
+        .write_str(&lines.replace('6', "six"))
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+
+    let assertion = fixup(&td)
+        .args(["--stop-on-conflict", "-P", "target"])
+        .assert()
+        .failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("Stopped at a conflict while applying"),
+        "out: {}",
+        out
+    );
+
+    td.child("lines.txt")
+        .write_str("1\n2\n3\n4\nfive\nsix\n7\n8\n9\n")
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    fixup(&td).arg("--continue").assert().failure();
+    td.child("lines.txt")
+        .write_str(&lines.replace('6', "six"))
+        .unwrap();
+    git(&["add", "lines.txt"], &td);
+    fixup(&td).arg("--continue").assert().success();
+
+    let expected = "\
+* revert HEAD -> changes
+* change
+* target root
+";
+    let out = git_log(&td);
+    assert_eq!(out, expected, "\nactual:\n{}\nexpected:\n{}", out, expected);
+    assert_eq!(
+        string(git_out(&["show", "root:lines.txt"], &td).stdout),
+        lines.replace('6', "six")
+    );
+}
+
+#[test]
+fn signs_rewritten_commits() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a", "b"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    git_commits(&["target", "d"], &td);
+
+    let key = td.path().join("signing_key");
+    let keygen = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&key)
+        .output()
+        .unwrap();
+    assert!(keygen.status.success(), "{}", string(keygen.stderr));
+    git(&["config", "commit.gpgsign", "true"], &td);
+    git(&["config", "gpg.format", "ssh"], &td);
+    git(&["config", "user.signingkey", key.to_str().unwrap()], &td);
+
+    td.child("file_target").write_str("fixed").unwrap();
+    git(&["add", "file_target"], &td);
+    fixup(&td).args(["-P", "target"]).assert().success();
+
+    for rev in ["HEAD", "HEAD~"] {
+        let commit = string(git_out(&["cat-file", "commit", rev], &td).stdout);
+        assert!(
+            commit.contains("gpgsig -----BEGIN SSH SIGNATURE-----"),
+            "{rev}: {commit}"
+        );
+    }
+
+    // a signer that doesn't work stops the rewrite before anything changes
+    git(&["config", "gpg.ssh.program", "false"], &td);
+    let head = string(git_out(&["rev-parse", "HEAD"], &td).stdout);
+    td.child("file_target").write_str("fixed again").unwrap();
+    git(&["add", "file_target"], &td);
+    let assertion = fixup(&td).args(["--yes", "-P", "target"]).assert().code(3);
+    let out = string(assertion.get_output().stderr.clone());
+    assert!(out.contains("can't be signed"), "out: {}", out);
+    assert_eq!(string(git_out(&["rev-parse", "HEAD"], &td).stdout), head);
+}
+
+#[test]
+fn commit_message_pattern_scopes() {
+    let td = assert_fs::TempDir::new().unwrap();
+    git_init(&td);
+
+    git_commits(&["a"], &td);
+    git(&["checkout", "-b", "changes"], &td);
+    git(&["branch", "-u", "main"], &td);
+    td.child("file_target").touch().unwrap();
+    git(&["add", "-A"], &td);
+    git(&["commit", "-m", "target-1", "-m", "Refs: ISSUE-42"], &td);
+    git_commits(&["target-2", "target-3"], &td);
+
+    // more than one commit matches, and there's no terminal to choose on
+    td.child("ambiguous").touch().unwrap();
+    git(&["add", "ambiguous"], &td);
+    let assertion = fixup(&td).args(["-P", "target"]).assert().failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("Multiple commits match the pattern in their summary"),
+        "out: {}",
+        out
+    );
+    assert!(
+        out.contains("target-1") && out.contains("target-3"),
+        "out: {}",
+        out
+    );
+
+    fixup(&td)
+        .args(["-P", "t-2$", "--pattern-regex"])
+        .assert()
+        .success();
+    let (files, err) = git_changed_files("target-2", &td);
+    assert_eq!(
+        files, "ambiguous\nfile_target-2\n",
+        "out: {} err: {}",
+        files, err
+    );
+
+    td.child("by-trailer").touch().unwrap();
+    git(&["add", "by-trailer"], &td);
+    fixup(&td)
+        .args(["-P", "ISSUE-42", "--pattern-scope", "trailer:refs"])
+        .assert()
+        .success();
+    let (files, err) = git_changed_files("target-1", &td);
+    assert_eq!(
+        files, "by-trailer\nfile_target\n",
+        "out: {} err: {}",
+        files, err
+    );
+
+    // the pattern is matched against every commit up to the merge-base, not just the first page
+    td.child("beyond-page").touch().unwrap();
+    git(&["add", "beyond-page"], &td);
+    fixup(&td)
+        .args(["--max-commits", "1", "-P", "target-1"])
+        .assert()
+        .success();
+    let (files, err) = git_changed_files("target-1", &td);
+    assert_eq!(
+        files, "beyond-page\nby-trailer\nfile_target\n",
+        "out: {} err: {}",
+        files, err
+    );
+
+    // without an upstream only the first page is searched, and it says so
+    git(&["branch", "--unset-upstream"], &td);
+    git(&["branch", "-m", "main", "base"], &td);
+    td.child("no-upstream").touch().unwrap();
+    git(&["add", "no-upstream"], &td);
+    let assertion = fixup(&td)
+        .args(["--max-commits", "1", "-P", "target-1"])
+        .assert()
+        .failure();
+    let out = string(assertion.get_output().stdout.clone());
+    assert!(
+        out.contains("Older commits were not searched since HEAD has no upstream"),
+        "out: {}",
+        out
+    );
+}
+
+fn write_hook(tempdir: &assert_fs::TempDir, name: &str, script: &str) {
+    use std::os::unix::fs::PermissionsExt as _;
+    let hook = tempdir.child(format!(".git/hooks/{name}"));
+    hook.write_str(&format!("#!/bin/sh\n{script}\n")).unwrap();
+    std::fs::set_permissions(hook.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
 fn git_commits(ids: &[&str], tempdir: &assert_fs::TempDir) {
     for n in ids {
         git_file_commit(n, tempdir);
@@ -543,10 +2103,19 @@ fn git_out(args: &[&str], tempdir: &assert_fs::TempDir) -> Output {
 
 fn git_log(tempdir: &assert_fs::TempDir) -> String {
     let mut s = String::from_utf8(
-        git_inner(&["log", "--all", "--format=%s %D", "--graph"], tempdir)
-            .output()
-            .unwrap()
-            .stdout,
+        git_inner(
+            &[
+                "log",
+                "--exclude=refs/notes/*",
+                "--all",
+                "--format=%s %D",
+                "--graph",
+            ],
+            tempdir,
+        )
+        .output()
+        .unwrap()
+        .stdout,
     )
     .unwrap()
     .lines()